@@ -0,0 +1,209 @@
+use crate::errors::GenericError;
+use crate::types::{AnswerExtractionStrategy, AnswerExtractionTrace};
+use crate::utils::{clean_llm_response, remove_think_blocks};
+use regex::Regex;
+
+/// Checks that the strategy parameters are valid, before running an evaluation
+pub fn validate_answer_extraction_strategy(
+    strategy: &AnswerExtractionStrategy,
+) -> Result<(), GenericError> {
+    match strategy {
+        AnswerExtractionStrategy::Regex(pattern) => Regex::new(pattern).map(|_| ()).map_err(|e| {
+            GenericError::new(
+                GenericError::INVALID_ARGUMENT,
+                format!("Invalid answer extraction pattern: {}", e),
+            )
+        }),
+        AnswerExtractionStrategy::JsonField(field) if field.trim().is_empty() => Err(GenericError::new(
+            GenericError::EMPTY_INPUT,
+            "Answer extraction JSON field cannot be empty.",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Removes the whitespace, quotes, markdown and punctuation around a candidate answer
+fn normalize_candidate(candidate: &str) -> &str {
+    candidate.trim().trim_matches(|c: char| {
+        c.is_whitespace()
+            || matches!(c, '.' | ',' | ';' | ':' | '!' | '?' | '"' | '\'' | '*' | '`' | '(' | ')' | '[' | ']')
+    })
+}
+
+fn first_matching_token(input: &str, values: &[&str]) -> Result<String, String> {
+    input
+        .split_whitespace()
+        .map(normalize_candidate)
+        .find(|token| values.contains(token))
+        .map(|token| token.to_string())
+        .ok_or_else(|| "No token matches the expected values".to_string())
+}
+
+fn regex_capture(input: &str, pattern: &str) -> Result<String, String> {
+    let re = Regex::new(pattern).map_err(|e| format!("Invalid pattern: {}", e))?;
+    let captures = re
+        .captures(input)
+        .ok_or_else(|| "Pattern doesn't match the response".to_string())?;
+
+    Ok(normalize_candidate(captures.get(1).or(captures.get(0)).unwrap().as_str()).to_string())
+}
+
+fn json_field(input: &str, field: &str) -> Result<String, String> {
+    let (start, end) = match (input.find('{'), input.rfind('}')) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => return Err("No JSON object found in the response".to_string()),
+    };
+
+    let value: serde_json::Value = serde_json::from_str(&input[start..=end])
+        .map_err(|e| format!("Invalid JSON object: {}", e))?;
+
+    match value.get(field) {
+        Some(serde_json::Value::String(answer)) => Ok(normalize_candidate(answer).to_string()),
+        Some(serde_json::Value::Null) | None => Err(format!("Field '{}' not found", field)),
+        Some(answer) => Ok(answer.to_string()),
+    }
+}
+
+/// Last non-empty line. If it's labeled (e.g. "Answer: H"), only the text after the last colon is used
+fn last_line(input: &str) -> Result<String, String> {
+    let line = input
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .ok_or_else(|| "Empty response".to_string())?;
+
+    let answer = match line.rsplit_once(':') {
+        Some((_, answer)) if !normalize_candidate(answer).is_empty() => answer,
+        _ => line,
+    };
+
+    Ok(normalize_candidate(answer).to_string())
+}
+
+/// Extracts the answer of a LLM response, that should be one of the expected values.
+///
+/// # Parameters
+/// - `response`: raw response of the LLM.
+/// - `values`: expected answers.
+/// - `strategy`: how the answer is found in the response.
+///
+/// # Returns
+/// - The index of the value found, or an error if no valid answer was found, and the extraction trace.
+pub fn extract_answer_index(
+    response: &str,
+    values: &[&str],
+    strategy: &AnswerExtractionStrategy,
+) -> (Result<usize, String>, AnswerExtractionTrace) {
+    let input = match strategy {
+        // Keeps the previous behaviour, where line breaks were replaced by spaces
+        AnswerExtractionStrategy::ExactMatch => clean_llm_response(response),
+        _ => remove_think_blocks(response).trim().to_string(),
+    };
+
+    let extracted = match strategy {
+        AnswerExtractionStrategy::ExactMatch => Ok(input.clone()),
+        AnswerExtractionStrategy::FirstMatchingToken => first_matching_token(&input, values),
+        AnswerExtractionStrategy::Regex(pattern) => regex_capture(&input, pattern),
+        AnswerExtractionStrategy::JsonField(field) => json_field(&input, field),
+        AnswerExtractionStrategy::LastLine => last_line(&input),
+    };
+
+    let index = extracted.clone().and_then(|answer| {
+        values
+            .iter()
+            .position(|value| *value == answer)
+            .ok_or_else(|| format!("Unknown response '{}'", answer))
+    });
+
+    let trace = AnswerExtractionTrace {
+        strategy: strategy.clone(),
+        input,
+        extracted: extracted.ok(),
+        error: index.clone().err(),
+    };
+
+    (index, trace)
+}
+
+/// Extracts the binarized answer of a LLM fairness response.
+/// The first predict attribute value corresponds to `false`, and the second one to `true`.
+pub fn extract_answer(
+    response: &str,
+    predict_attributes_values: &[&str; 2],
+    strategy: &AnswerExtractionStrategy,
+) -> (Result<bool, String>, AnswerExtractionTrace) {
+    let (index, trace) = extract_answer_index(response, predict_attributes_values, strategy);
+    (index.map(|index| index == 1), trace)
+}
+
+/// Text to re-parse from a stored response.
+/// The trace input keeps the line breaks, so it's preferred over the cleaned response.
+pub fn stored_response(
+    response: &Option<String>,
+    answer_extraction: &Option<AnswerExtractionTrace>,
+) -> Option<String> {
+    match answer_extraction {
+        Some(trace) if trace.strategy != AnswerExtractionStrategy::ExactMatch => Some(trace.input.clone()),
+        _ => response.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALUES: [&str; 2] = ["L", "H"];
+
+    fn extract(response: &str, strategy: AnswerExtractionStrategy) -> Result<bool, String> {
+        extract_answer(response, &VALUES, &strategy).0
+    }
+
+    #[test]
+    fn test_exact_match() {
+        assert_eq!(extract("<think>Maybe L</think> H ", AnswerExtractionStrategy::ExactMatch), Ok(true));
+        assert!(extract("H.", AnswerExtractionStrategy::ExactMatch).is_err());
+    }
+
+    #[test]
+    fn test_first_matching_token() {
+        assert_eq!(extract("Answer: H.", AnswerExtractionStrategy::FirstMatchingToken), Ok(true));
+        assert_eq!(extract("**L** is the answer", AnswerExtractionStrategy::FirstMatchingToken), Ok(false));
+        assert!(extract("High", AnswerExtractionStrategy::FirstMatchingToken).is_err());
+    }
+
+    #[test]
+    fn test_regex() {
+        let strategy = AnswerExtractionStrategy::Regex(r"score is (\w+)".to_string());
+        assert_eq!(extract("I think the score is L.", strategy.clone()), Ok(false));
+        assert!(extract("No idea", strategy).is_err());
+
+        assert!(validate_answer_extraction_strategy(&AnswerExtractionStrategy::Regex("(".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_json_field() {
+        let strategy = AnswerExtractionStrategy::JsonField("answer".to_string());
+        assert_eq!(
+            extract("Sure! ```json\n{\"answer\": \"H\", \"reason\": \"...\"}\n```", strategy.clone()),
+            Ok(true)
+        );
+        assert!(extract("{\"label\": \"H\"}", strategy).is_err());
+    }
+
+    #[test]
+    fn test_last_line_and_trace() {
+        let (predicted, trace) = extract_answer(
+            "The student reads a lot.\nFinal answer: L\n",
+            &VALUES,
+            &AnswerExtractionStrategy::LastLine,
+        );
+        assert_eq!(predicted, Ok(false));
+        assert_eq!(trace.extracted, Some("L".to_string()));
+        assert_eq!(trace.error, None);
+
+        let (predicted, trace) = extract_answer("Medium", &VALUES, &AnswerExtractionStrategy::LastLine);
+        assert!(predicted.is_err());
+        assert_eq!(trace.extracted, Some("Medium".to_string()));
+        assert!(trace.error.is_some());
+    }
+}
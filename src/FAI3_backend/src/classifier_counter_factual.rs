@@ -0,0 +1,449 @@
+use crate::errors::GenericError;
+use crate::metrics_calculation::{calculate_medians, is_privileged_value};
+use crate::types::{
+    get_classifier_model_data, ClassifierCounterFactualResult, ClassifierPredictor,
+    CounterFactualModelEvaluationResult, DataPoint, ModelType,
+};
+use crate::utils::is_owner;
+use crate::{check_cycles_before_action, get_model_from_memory, MODELS};
+use std::collections::HashMap;
+
+/// Sensible attributes of a dataset and the values they take,
+/// used to build the flipped variants of every data point.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CounterFactualFlips {
+    // Attribute name and feature index, sorted by name so results are stable
+    pub attributes: Vec<(String, usize)>,
+    // Distinct values observed for every feature index, up to three
+    values: HashMap<usize, Vec<f64>>,
+}
+
+impl CounterFactualFlips {
+    pub fn from_data_points(data_points: &Vec<DataPoint>) -> Result<Self, String> {
+        if data_points.is_empty() {
+            return Err("There are no data points to test".to_string());
+        }
+
+        let mut attributes: Vec<(String, usize)> = data_points[0]
+            .privileged_map
+            .iter()
+            .map(|(name, index)| (name.clone(), *index as usize))
+            .collect();
+        attributes.sort();
+
+        if attributes.is_empty() {
+            return Err("Data points should have at least one sensible attribute".to_string());
+        }
+
+        let mut values: HashMap<usize, Vec<f64>> = HashMap::new();
+        for point in data_points {
+            for (_, index) in &attributes {
+                let value = *point
+                    .features
+                    .get(*index)
+                    .ok_or(format!("Feature index {} is out of bounds", index))?;
+                if value.is_nan() {
+                    continue;
+                }
+                let observed = values.entry(*index).or_default();
+                // Only binary attributes are flipped, so there is no need to keep more than three values
+                if observed.len() < 3 && !observed.contains(&value) {
+                    observed.push(value);
+                }
+            }
+        }
+
+        let flips = Self { attributes, values };
+        if !(0..flips.attributes.len()).any(|attribute| flips.can_flip(attribute)) {
+            return Err("None of the sensible attributes takes exactly two values, so they cannot be flipped".to_string());
+        }
+
+        Ok(flips)
+    }
+
+    /// Whether the sensible attribute in position `attribute` takes exactly two values in the dataset
+    pub fn can_flip(&self, attribute: usize) -> bool {
+        self.attributes
+            .get(attribute)
+            .and_then(|(_, index)| self.values.get(index))
+            .is_some_and(|values| values.len() == 2)
+    }
+
+    /// Returns the features with the sensible attribute in position `attribute` swapped with its other value.
+    /// Returns None if the value cannot be flipped: the attribute doesn't take exactly two values,
+    /// or the value is missing, NaN or not one of them. Those rows are not tested for the attribute.
+    pub fn flipped_features(&self, features: &[f64], attribute: usize) -> Option<Vec<f64>> {
+        if !self.can_flip(attribute) {
+            return None;
+        }
+        let index = self.attributes[attribute].1;
+        let value = *features.get(index)?;
+        let values = &self.values[&index];
+
+        let other = if value == values[0] {
+            values[1]
+        } else if value == values[1] {
+            values[0]
+        } else {
+            return None;
+        };
+
+        let mut flipped = features.to_vec();
+        flipped[index] = other;
+        Some(flipped)
+    }
+}
+
+/// Aggregates counter factual predictions into change rates.
+///
+/// # Parameters
+/// - `data_points`: data points tested. Only features and privileged_map are used.
+/// - `flips`: sensible attributes used to build the variants.
+/// - `original`: prediction for every data point without changes.
+/// - `flipped`: for every data point, the prediction with each sensible attribute flipped. None means it couldn't be calculated.
+/// - `privileged_threshold`: thresholds used to split groups. If None, the middle of the range is used.
+///
+/// # Returns
+/// - Tuple with overall change rate, total of data points used, and a `CounterFactualModelEvaluationResult` for each sensible attribute.
+///   In `change_rate_sensible_attributes` and `total_sensible_attributes`, index 0 is the unprivileged group and index 1 the privileged group.
+pub fn aggregate_counter_factual_predictions(
+    data_points: &Vec<DataPoint>,
+    flips: &CounterFactualFlips,
+    original: &[bool],
+    flipped: &[Vec<Option<bool>>],
+    privileged_threshold: Option<HashMap<String, (f64, bool)>>,
+) -> (f32, u32, Vec<CounterFactualModelEvaluationResult>) {
+    let thresholds = privileged_threshold.unwrap_or_else(|| calculate_medians(data_points));
+
+    let attributes = &flips.attributes;
+    let mut changed_any: u32 = 0;
+    let mut tested_points: u32 = 0;
+    let mut changed: Vec<[u32; 2]> = vec![[0, 0]; attributes.len()];
+    let mut totals: Vec<[u32; 2]> = vec![[0, 0]; attributes.len()];
+
+    for ((point, original), flipped) in data_points.iter().zip(original).zip(flipped) {
+        let mut point_changed = false;
+        let mut point_tested = false;
+
+        for (attr_idx, (name, index)) in attributes.iter().enumerate() {
+            let counter_factual = match flipped.get(attr_idx) {
+                Some(Some(prediction)) => *prediction,
+                _ => continue,
+            };
+
+            let value = point.features[*index];
+            let threshold = *thresholds.get(name).unwrap_or(&(0.0, true));
+            let group = if is_privileged_value(value, threshold) { 1 } else { 0 };
+
+            point_tested = true;
+            totals[attr_idx][group] += 1;
+            if counter_factual != *original {
+                changed[attr_idx][group] += 1;
+                point_changed = true;
+            }
+        }
+
+        if point_tested {
+            tested_points += 1;
+        }
+        if point_changed {
+            changed_any += 1;
+        }
+    }
+
+    let rate = |changed: u32, total: u32| -> f32 {
+        if total == 0 {
+            0.0
+        } else {
+            changed as f32 / total as f32
+        }
+    };
+
+    let results = attributes
+        .iter()
+        .enumerate()
+        .map(|(attr_idx, (name, _))| {
+            let total = totals[attr_idx][0] + totals[attr_idx][1];
+            let changed_total = changed[attr_idx][0] + changed[attr_idx][1];
+            CounterFactualModelEvaluationResult {
+                change_rate_overall: rate(changed_total, total),
+                change_rate_sensible_attributes: vec![
+                    rate(changed[attr_idx][0], totals[attr_idx][0]),
+                    rate(changed[attr_idx][1], totals[attr_idx][1]),
+                ],
+                total_sensible_attributes: totals[attr_idx].to_vec(),
+                sensible_attribute: name.clone(),
+                change_rate_value_pairs: None,
+            }
+        })
+        .collect();
+
+    (rate(changed_any, tested_points), tested_points, results)
+}
+
+/// Calculates counter factual fairness for a classifier whose predictions can be computed in the canister.
+/// For every binary sensible attribute in the privileged map, the feature is flipped and the prediction is recomputed.
+/// The original rows are predicted with the same predictor, so a mismatch with the stored predictions doesn't count as a change.
+pub fn calculate_classifier_counter_factual_metrics(
+    data_points: &Vec<DataPoint>,
+    predictor: &ClassifierPredictor,
+    privileged_threshold: Option<HashMap<String, (f64, bool)>>,
+) -> Result<(f32, u32, Vec<CounterFactualModelEvaluationResult>), String> {
+    let flips = CounterFactualFlips::from_data_points(data_points)?;
+
+    let original: Vec<bool> = data_points
+        .iter()
+        .map(|point| predictor.predict(&point.features))
+        .collect::<Result<_, _>>()?;
+    let mut flipped: Vec<Vec<Option<bool>>> = Vec::with_capacity(data_points.len());
+
+    for point in data_points {
+        let mut point_flipped = Vec::with_capacity(flips.attributes.len());
+        for attribute in 0..flips.attributes.len() {
+            let prediction = match flips.flipped_features(&point.features, attribute) {
+                Some(features) => Some(predictor.predict(&features)?),
+                None => None,
+            };
+            point_flipped.push(prediction);
+        }
+        flipped.push(point_flipped);
+    }
+
+    Ok(aggregate_counter_factual_predictions(
+        data_points,
+        &flips,
+        &original,
+        &flipped,
+        privileged_threshold,
+    ))
+}
+
+/// Runs the counter factual flip test for a classifier model.
+/// It requires a predictor to be set with `set_classifier_predictor`.
+/// It uses the data points with the latest timestamp and the cached thresholds, if any.
+///
+/// # Parameters
+/// - `model_id: u128`: classifier model id.
+///
+/// # Returns
+/// - `Result<ClassifierCounterFactualResult, GenericError>`: the result, which is also saved in the model.
+#[ic_cdk::update]
+pub fn calculate_classifier_counter_factual(
+    model_id: u128,
+) -> Result<ClassifierCounterFactualResult, GenericError> {
+    check_cycles_before_action();
+    let caller = ic_cdk::api::caller();
+
+    let model = get_model_from_memory(model_id)?;
+    is_owner(&model, caller);
+
+    let model_data = match &model.model_type {
+        ModelType::Classifier(_) => get_classifier_model_data(&model),
+        _ => {
+            return Err(GenericError::new(
+                GenericError::INVALID_MODEL_TYPE,
+                "Model should be a classifier.",
+            ))
+        }
+    };
+
+    let predictor = model_data.predictor.clone().ok_or(GenericError::new(
+        GenericError::NOT_FOUND,
+        "Model has no predictor set. Use set_classifier_predictor first.",
+    ))?;
+
+    let latest_timestamp = match model_data.data_points.last() {
+        Some(point) => point.timestamp,
+        None => {
+            return Err(GenericError::new(
+                GenericError::EMPTY_INPUT,
+                "Model has no data points.",
+            ))
+        }
+    };
+
+    let data_points: Vec<DataPoint> = model_data
+        .data_points
+        .iter()
+        .filter(|point| point.timestamp == latest_timestamp)
+        .cloned()
+        .collect();
+
+    let thresholds = model
+        .cached_thresholds
+        .as_ref()
+        .and_then(|cached| cached.thresholds.clone());
+
+    let (change_rate_overall, total_data_points, sensible_attributes) =
+        calculate_classifier_counter_factual_metrics(&data_points, &predictor, thresholds)
+            .map_err(|e| GenericError::new(GenericError::INVALID_ARGUMENT, e))?;
+
+    let result = ClassifierCounterFactualResult {
+        change_rate_overall,
+        total_data_points,
+        sensible_attributes,
+        timestamp: ic_cdk::api::time(),
+    };
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&model_id).expect("Model not found");
+        let mut model_data = get_classifier_model_data(&model);
+        model_data.counter_factual = Some(result.clone());
+        model.model_type = ModelType::Classifier(model_data);
+        models.insert(model_id, model);
+    });
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::LogisticRegressionModel;
+
+    fn data_point(id: u128, features: Vec<f64>, predicted: bool) -> DataPoint {
+        let mut privileged_map = HashMap::new();
+        privileged_map.insert("gender".to_string(), 0);
+        DataPoint {
+            data_point_id: id,
+            target: true,
+            privileged_map,
+            predicted,
+            features,
+            timestamp: 1,
+        }
+    }
+
+    fn predictor(weights: Vec<f64>) -> ClassifierPredictor {
+        ClassifierPredictor::LogisticRegression(LogisticRegressionModel {
+            weights,
+            intercept: 0.0,
+            threshold: 0.5,
+        })
+    }
+
+    #[test]
+    fn test_no_change_when_sensible_feature_is_ignored() {
+        let data_points = vec![
+            data_point(0, vec![0.0, 1.0], true),
+            data_point(1, vec![1.0, -1.0], false),
+        ];
+
+        let (overall, total, results) =
+            calculate_classifier_counter_factual_metrics(&data_points, &predictor(vec![0.0, 1.0]), None)
+                .unwrap();
+
+        assert_eq!(overall, 0.0);
+        assert_eq!(total, 2);
+        assert_eq!(results[0].sensible_attribute, "gender");
+        assert_eq!(results[0].total_sensible_attributes, vec![1, 1]);
+    }
+
+    #[test]
+    fn test_every_prediction_changes_when_model_only_uses_sensible_feature() {
+        let data_points = vec![
+            data_point(0, vec![0.0, 1.0], false),
+            data_point(1, vec![1.0, 1.0], true),
+            data_point(2, vec![1.0, 1.0], true),
+        ];
+
+        // x0 = 1 -> positive, x0 = 0 -> negative
+        let model = ClassifierPredictor::LogisticRegression(LogisticRegressionModel {
+            weights: vec![4.0, 0.0],
+            intercept: -2.0,
+            threshold: 0.5,
+        });
+
+        let (overall, _, results) =
+            calculate_classifier_counter_factual_metrics(&data_points, &model, None).unwrap();
+
+        assert_eq!(overall, 1.0);
+        assert_eq!(results[0].change_rate_overall, 1.0);
+        assert_eq!(results[0].change_rate_sensible_attributes, vec![1.0, 1.0]);
+        assert_eq!(results[0].total_sensible_attributes, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_stored_predictions_are_not_compared() {
+        // Stored predictions disagree with the predictor, which ignores the sensible feature
+        let data_points = vec![
+            data_point(0, vec![0.0, 1.0], false),
+            data_point(1, vec![1.0, -1.0], true),
+        ];
+
+        let (overall, total, results) =
+            calculate_classifier_counter_factual_metrics(&data_points, &predictor(vec![0.0, 1.0]), None)
+                .unwrap();
+
+        assert_eq!(overall, 0.0);
+        assert_eq!(total, 2);
+        assert_eq!(results[0].change_rate_overall, 0.0);
+    }
+
+    #[test]
+    fn test_binary_attribute_is_swapped() {
+        let data_points = vec![
+            data_point(0, vec![1.0, 5.0], true),
+            data_point(1, vec![2.0, 5.0], true),
+            data_point(2, vec![f64::NAN, 5.0], true),
+        ];
+
+        let flips = CounterFactualFlips::from_data_points(&data_points).unwrap();
+
+        assert_eq!(flips.flipped_features(&[1.0, 5.0], 0), Some(vec![2.0, 5.0]));
+        assert_eq!(flips.flipped_features(&[2.0, 5.0], 0), Some(vec![1.0, 5.0]));
+        assert_eq!(flips.flipped_features(&[f64::NAN, 5.0], 0), None);
+        assert_eq!(flips.flipped_features(&[3.0, 5.0], 0), None);
+    }
+
+    #[test]
+    fn test_attributes_without_two_values_cannot_be_flipped() {
+        let non_binary = vec![
+            data_point(0, vec![18.0, 1.0], true),
+            data_point(1, vec![60.0, 1.0], true),
+            data_point(2, vec![30.0, 1.0], true),
+        ];
+        let constant = vec![
+            data_point(0, vec![1.0, 1.0], true),
+            data_point(1, vec![1.0, 1.0], true),
+        ];
+
+        assert!(CounterFactualFlips::from_data_points(&non_binary).is_err());
+        assert!(CounterFactualFlips::from_data_points(&constant).is_err());
+    }
+
+    #[test]
+    fn test_points_without_flipped_predictions_are_skipped() {
+        let data_points = vec![
+            data_point(0, vec![0.0, 1.0], true),
+            data_point(1, vec![1.0, 1.0], true),
+        ];
+        let flips = CounterFactualFlips::from_data_points(&data_points).unwrap();
+
+        let (overall, total, results) = aggregate_counter_factual_predictions(
+            &data_points,
+            &flips,
+            &[true, true],
+            &[vec![Some(false)], vec![None]],
+            None,
+        );
+
+        assert_eq!(overall, 1.0);
+        assert_eq!(total, 1);
+        assert_eq!(results[0].total_sensible_attributes, vec![1, 0]);
+    }
+
+    #[test]
+    fn test_wrong_feature_count_returns_error() {
+        let data_points = vec![
+            data_point(0, vec![0.0, 1.0], true),
+            data_point(1, vec![1.0, 1.0], true),
+        ];
+
+        let result =
+            calculate_classifier_counter_factual_metrics(&data_points, &predictor(vec![1.0]), None);
+
+        assert!(result.is_err());
+    }
+}
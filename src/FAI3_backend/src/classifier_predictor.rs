@@ -0,0 +1,113 @@
+use crate::errors::GenericError;
+use crate::types::{
+    get_classifier_model_data, ClassifierPredictor, LogisticRegressionModel, ModelType,
+};
+use crate::utils::is_owner;
+use crate::{check_cycles_before_action, get_model_from_memory, MODELS};
+
+impl LogisticRegressionModel {
+    /// Returns the probability of the positive class for a feature vector
+    pub fn score(&self, features: &[f64]) -> Result<f64, String> {
+        if features.len() != self.weights.len() {
+            return Err(format!(
+                "Expected {} features but got {}",
+                self.weights.len(),
+                features.len()
+            ));
+        }
+
+        let linear: f64 = self
+            .weights
+            .iter()
+            .zip(features.iter())
+            .map(|(w, x)| w * x)
+            .sum::<f64>()
+            + self.intercept;
+
+        Ok(1.0 / (1.0 + (-linear).exp()))
+    }
+}
+
+impl ClassifierPredictor {
+    /// Recomputes the prediction for a feature vector.
+    /// Only predictors that run inside the canister are supported, HTTP endpoints are called from jobs.
+    pub fn predict(&self, features: &[f64]) -> Result<bool, String> {
+        match self {
+            ClassifierPredictor::LogisticRegression(model) => {
+                Ok(model.score(features)? >= model.threshold)
+            }
+            ClassifierPredictor::Http(_) => Err(
+                "HTTP predictors cannot be evaluated synchronously. Use evaluate_remote_classifier instead."
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// Sets the model used to recompute predictions for a classifier.
+///
+/// # Parameters
+/// - `model_id: u128`: classifier model id.
+/// - `predictor: ClassifierPredictor`: predictor definition. Weights should be aligned with the data points features.
+#[ic_cdk::update]
+pub fn set_classifier_predictor(
+    model_id: u128,
+    predictor: ClassifierPredictor,
+) -> Result<(), GenericError> {
+    check_cycles_before_action();
+    let caller = ic_cdk::api::caller();
+
+    let model = get_model_from_memory(model_id)?;
+    is_owner(&model, caller);
+
+    if !matches!(model.model_type, ModelType::Classifier(_)) {
+        return Err(GenericError::new(
+            GenericError::INVALID_MODEL_TYPE,
+            "Model should be a classifier.",
+        ));
+    }
+
+    match &predictor {
+        ClassifierPredictor::LogisticRegression(lr) => {
+            if lr.weights.is_empty() {
+                return Err(GenericError::new(
+                    GenericError::EMPTY_INPUT,
+                    "Weights cannot be empty.",
+                ));
+            }
+        }
+        ClassifierPredictor::Http(endpoint) => {
+            if !endpoint.url.starts_with("https://") {
+                return Err(GenericError::new(
+                    GenericError::INVALID_ARGUMENT,
+                    "Endpoint url should use https.",
+                ));
+            }
+            if endpoint.features_field.trim().is_empty() {
+                return Err(GenericError::new(
+                    GenericError::EMPTY_INPUT,
+                    "Features field cannot be empty.",
+                ));
+            }
+            if !endpoint.prediction_pointer.is_empty()
+                && !endpoint.prediction_pointer.starts_with('/')
+            {
+                return Err(GenericError::new(
+                    GenericError::INVALID_ARGUMENT,
+                    "Prediction pointer should be a JSON pointer, like /prediction.",
+                ));
+            }
+        }
+    }
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&model_id).expect("Model not found");
+        let mut model_data = get_classifier_model_data(&model);
+        model_data.predictor = Some(predictor);
+        model.model_type = ModelType::Classifier(model_data);
+        models.insert(model_id, model);
+    });
+
+    Ok(())
+}
@@ -0,0 +1,444 @@
+use crate::errors::GenericError;
+use crate::types::{
+    get_embedding_model_data, Embedding, EmbeddingAssociationResult, EmbeddingAssociationTest,
+    EmbeddingAssociationTestKind, EmbeddingAssociationTestResult, EmbeddingAssociationWordSet, ModelType,
+};
+use crate::utils::{is_owner, seeded_vector_shuffle};
+use crate::{check_cycles_before_action, get_model_from_memory, MODELS};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+const WEAT_TESTS_JSON: &str = include_str!("data/weat_tests.json");
+
+// Limit of permutations per test, so all the tests fit in a single call
+const MAX_PERMUTATIONS: u32 = 10_000;
+
+/// Bundled WEAT tests, and the templates used to build SEAT sentences from their words
+#[derive(Deserialize, Debug, Clone)]
+struct EmbeddingAssociationData {
+    sentence_templates: Vec<String>,
+    tests: Vec<EmbeddingAssociationTest>,
+}
+
+fn load_data() -> Result<EmbeddingAssociationData, GenericError> {
+    serde_json::from_str(WEAT_TESTS_JSON).map_err(|e| {
+        GenericError::new(
+            GenericError::INVALID_RESOURCE_FORMAT,
+            format!("Error parsing WEAT tests: {}", e),
+        )
+    })
+}
+
+/// Texts of a word set that need an embedding: the words, or a sentence for every word and template
+fn stimuli(
+    word_set: &EmbeddingAssociationWordSet,
+    kind: EmbeddingAssociationTestKind,
+    sentence_templates: &[String],
+) -> Vec<String> {
+    match kind {
+        EmbeddingAssociationTestKind::Word => word_set.words.clone(),
+        EmbeddingAssociationTestKind::Sentence => word_set
+            .words
+            .iter()
+            .flat_map(|word| sentence_templates.iter().map(move |template| template.replace("{}", word)))
+            .collect(),
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    let mut dot = 0.0;
+    let mut norm_a = 0.0;
+    let mut norm_b = 0.0;
+    for (x, y) in a.iter().zip(b.iter()) {
+        let (x, y) = (*x as f64, *y as f64);
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// s(w, A, B): mean similarity of w with the attributes A minus its mean similarity with the attributes B
+fn association(w: &[f32], a: &[&[f32]], b: &[&[f32]]) -> f64 {
+    let with_a: Vec<f64> = a.iter().map(|v| cosine_similarity(w, v)).collect();
+    let with_b: Vec<f64> = b.iter().map(|v| cosine_similarity(w, v)).collect();
+    mean(&with_a) - mean(&with_b)
+}
+
+/// Effect size of Caliskan et al.: difference of the mean associations of X and Y,
+/// divided by the sample standard deviation of the associations of all the targets.
+/// None if the standard deviation is 0.
+fn effect_size(x_associations: &[f64], y_associations: &[f64]) -> Option<f64> {
+    let all: Vec<f64> = x_associations.iter().chain(y_associations.iter()).cloned().collect();
+    let all_mean = mean(&all);
+    let variance = all.iter().map(|s| (s - all_mean).powi(2)).sum::<f64>() / (all.len() - 1) as f64;
+    if variance == 0.0 {
+        return None;
+    }
+    Some((mean(x_associations) - mean(y_associations)) / variance.sqrt())
+}
+
+/// One-sided p-value: share of random partitions of X ∪ Y, with the sizes of X and Y,
+/// whose test statistic is greater or equal than the observed one
+fn permutation_p_value(x_associations: &[f64], y_associations: &[f64], permutations: u32, seed: u32) -> f64 {
+    let observed = x_associations.iter().sum::<f64>() - y_associations.iter().sum::<f64>();
+    let all: Vec<f64> = x_associations.iter().chain(y_associations.iter()).cloned().collect();
+
+    let mut state = seed;
+    let mut greater_or_equal = 0;
+    for _ in 0..permutations {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        let shuffled = seeded_vector_shuffle(all.clone(), state);
+        let (x, y) = shuffled.split_at(x_associations.len());
+        let statistic = x.iter().sum::<f64>() - y.iter().sum::<f64>();
+        // Tolerance for the rounding of sums in a different order
+        if statistic >= observed - 1e-9 {
+            greater_or_equal += 1;
+        }
+    }
+
+    greater_or_equal as f64 / permutations as f64
+}
+
+/// Runs a single test with the uploaded embeddings. Texts without an embedding are skipped.
+/// The metrics are None if a set has no embeddings, or if there are less than two targets.
+fn run_association_test(
+    test: &EmbeddingAssociationTest,
+    kind: EmbeddingAssociationTestKind,
+    sentence_templates: &[String],
+    embeddings: &HashMap<&str, &[f32]>,
+    permutations: u32,
+    seed: u32,
+) -> EmbeddingAssociationTestResult {
+    let mut missing = Vec::new();
+    let mut vectors = |word_set: &EmbeddingAssociationWordSet| -> Vec<&[f32]> {
+        let mut vectors = Vec::new();
+        for text in stimuli(word_set, kind, sentence_templates) {
+            match embeddings.get(text.as_str()) {
+                Some(vector) => vectors.push(*vector),
+                None => {
+                    if !missing.contains(&text) {
+                        missing.push(text);
+                    }
+                }
+            }
+        }
+        vectors
+    };
+    let (x, y, a, b) = (vectors(&test.x), vectors(&test.y), vectors(&test.a), vectors(&test.b));
+
+    let mut result = EmbeddingAssociationTestResult {
+        test_name: test.name.clone(),
+        x_count: x.len() as u32,
+        y_count: y.len() as u32,
+        a_count: a.len() as u32,
+        b_count: b.len() as u32,
+        missing,
+        test_statistic: None,
+        effect_size: None,
+        p_value: None,
+    };
+
+    if x.is_empty() || y.is_empty() || a.is_empty() || b.is_empty() || x.len() + y.len() < 2 {
+        return result;
+    }
+
+    let x_associations: Vec<f64> = x.iter().map(|w| association(w, &a, &b)).collect();
+    let y_associations: Vec<f64> = y.iter().map(|w| association(w, &a, &b)).collect();
+
+    result.test_statistic =
+        Some((x_associations.iter().sum::<f64>() - y_associations.iter().sum::<f64>()) as f32);
+    result.effect_size = effect_size(&x_associations, &y_associations).map(|e| e as f32);
+    if permutations > 0 {
+        result.p_value = Some(permutation_p_value(&x_associations, &y_associations, permutations, seed) as f32);
+    }
+
+    result
+}
+
+/// Checks the uploaded embeddings: texts can't be empty, and vectors must be finite and of the same dimension
+fn validate_embeddings(embeddings: &[Embedding], dimension: Option<usize>) -> Result<(), GenericError> {
+    let dimension = dimension.or(embeddings.first().map(|embedding| embedding.vector.len()));
+
+    for embedding in embeddings {
+        if embedding.text.trim().is_empty() {
+            return Err(GenericError::new(
+                GenericError::INVALID_ARGUMENT,
+                "Embedding texts cannot be empty",
+            ));
+        }
+        if embedding.vector.is_empty() || Some(embedding.vector.len()) != dimension {
+            return Err(GenericError::new(
+                GenericError::INVALID_ARGUMENT,
+                format!(
+                    "Embedding of \"{}\" should have {} dimensions",
+                    embedding.text,
+                    dimension.unwrap_or(0)
+                ),
+            ));
+        }
+        if embedding.vector.iter().any(|value| !value.is_finite()) {
+            return Err(GenericError::new(
+                GenericError::INVALID_ARGUMENT,
+                format!("Embedding of \"{}\" has values that are not finite", embedding.text),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the bundled WEAT tests, with their target and attribute word sets
+#[ic_cdk::query]
+pub fn get_embedding_association_tests() -> Result<Vec<EmbeddingAssociationTest>, GenericError> {
+    Ok(load_data()?.tests)
+}
+
+/// Returns all the texts that need an embedding to run the bundled tests.
+/// For `Sentence` tests, these are the sentences built with the words of the tests.
+#[ic_cdk::query]
+pub fn get_embedding_association_stimuli(kind: EmbeddingAssociationTestKind) -> Result<Vec<String>, GenericError> {
+    let data = load_data()?;
+
+    let mut texts: Vec<String> = Vec::new();
+    for test in &data.tests {
+        for word_set in [&test.x, &test.y, &test.a, &test.b] {
+            for text in stimuli(word_set, kind, &data.sentence_templates) {
+                if !texts.contains(&text) {
+                    texts.push(text);
+                }
+            }
+        }
+    }
+
+    Ok(texts)
+}
+
+/// Uploads word or sentence embeddings of an embedding model.
+/// Embeddings of texts that were already uploaded are replaced.
+/// Large uploads can be split into several calls.
+///
+/// # Parameters
+/// - `model_id: u128`: embedding model id.
+/// - `embeddings: Vec<Embedding>`: texts and their embedding vectors. All of them must have the same dimension.
+/// - `replace: bool`: if true, the previously uploaded embeddings are deleted.
+///
+/// # Returns
+/// - `Result<u32, GenericError>`: number of embeddings of the model after the upload.
+#[ic_cdk::update]
+pub fn upload_embeddings(model_id: u128, embeddings: Vec<Embedding>, replace: bool) -> Result<u32, GenericError> {
+    check_cycles_before_action();
+    let caller = ic_cdk::api::caller();
+
+    let model = get_model_from_memory(model_id)?;
+    is_owner(&model, caller);
+
+    let mut model_data = match &model.model_type {
+        ModelType::Embedding(_) => get_embedding_model_data(&model),
+        _ => {
+            return Err(GenericError::new(
+                GenericError::INVALID_MODEL_TYPE,
+                "Model should be an embedding model.",
+            ))
+        }
+    };
+
+    if replace {
+        model_data.embeddings = Vec::new();
+    }
+    let dimension = model_data.embeddings.first().map(|embedding| embedding.vector.len());
+    validate_embeddings(&embeddings, dimension)?;
+
+    for embedding in embeddings {
+        match model_data.embeddings.iter_mut().find(|e| e.text == embedding.text) {
+            Some(existing) => existing.vector = embedding.vector,
+            None => model_data.embeddings.push(embedding),
+        }
+    }
+    let total = model_data.embeddings.len() as u32;
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&model_id).expect("Model not found");
+        model.model_type = ModelType::Embedding(model_data);
+        models.insert(model_id, model);
+    });
+
+    Ok(total)
+}
+
+/// Runs the WEAT (word) or SEAT (sentence) embedding association tests with the uploaded embeddings.
+///
+/// # Parameters
+/// - `model_id: u128`: embedding model id.
+/// - `kind: EmbeddingAssociationTestKind`: whether word or sentence embeddings are used.
+/// - `test_names: Option<Vec<String>>`: tests to run. If it's None, all the bundled tests are run.
+/// - `permutations: u32`: number of random permutations of the p-value, up to 10000. If it's 0, p-values are not calculated.
+/// - `seed: u32`: seed of the permutations.
+///
+/// # Returns
+/// - `Result<EmbeddingAssociationResult, GenericError>`: the result, which is also saved in the model.
+#[ic_cdk::update]
+pub fn calculate_embedding_association_tests(
+    model_id: u128,
+    kind: EmbeddingAssociationTestKind,
+    test_names: Option<Vec<String>>,
+    permutations: u32,
+    seed: u32,
+) -> Result<EmbeddingAssociationResult, GenericError> {
+    check_cycles_before_action();
+    let caller = ic_cdk::api::caller();
+
+    let model = get_model_from_memory(model_id)?;
+    is_owner(&model, caller);
+
+    let model_data = match &model.model_type {
+        ModelType::Embedding(_) => get_embedding_model_data(&model),
+        _ => {
+            return Err(GenericError::new(
+                GenericError::INVALID_MODEL_TYPE,
+                "Model should be an embedding model.",
+            ))
+        }
+    };
+
+    if model_data.embeddings.is_empty() {
+        return Err(GenericError::new(
+            GenericError::EMPTY_INPUT,
+            "Model has no embeddings.",
+        ));
+    }
+
+    if permutations > MAX_PERMUTATIONS {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            format!("Permutations should be at most {}", MAX_PERMUTATIONS),
+        ));
+    }
+
+    let data = load_data()?;
+    let tests: Vec<&EmbeddingAssociationTest> = match &test_names {
+        Some(test_names) => test_names
+            .iter()
+            .map(|name| {
+                data.tests.iter().find(|test| &test.name == name).ok_or(GenericError::new(
+                    GenericError::NOT_FOUND,
+                    format!("Embedding association test {} not found", name),
+                ))
+            })
+            .collect::<Result<_, _>>()?,
+        None => data.tests.iter().collect(),
+    };
+
+    let embeddings: HashMap<&str, &[f32]> = model_data
+        .embeddings
+        .iter()
+        .map(|embedding| (embedding.text.as_str(), embedding.vector.as_slice()))
+        .collect();
+
+    let result = EmbeddingAssociationResult {
+        kind,
+        permutations,
+        seed,
+        results: tests
+            .into_iter()
+            .map(|test| run_association_test(test, kind, &data.sentence_templates, &embeddings, permutations, seed))
+            .collect(),
+        timestamp: ic_cdk::api::time(),
+    };
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&model_id).expect("Model not found");
+        let mut model_data = get_embedding_model_data(&model);
+        model_data.association_tests_history.push(result.clone());
+        model.model_type = ModelType::Embedding(model_data);
+        models.insert(model_id, model);
+    });
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word_set(words: &[&str]) -> EmbeddingAssociationWordSet {
+        EmbeddingAssociationWordSet {
+            name: String::new(),
+            words: words.iter().map(|word| word.to_string()).collect(),
+        }
+    }
+
+    fn test_definition() -> EmbeddingAssociationTest {
+        EmbeddingAssociationTest {
+            name: "test".to_string(),
+            description: String::new(),
+            x: word_set(&["rose", "tulip"]),
+            y: word_set(&["ant", "moth"]),
+            a: word_set(&["love"]),
+            b: word_set(&["hate"]),
+        }
+    }
+
+    #[test]
+    fn test_bundled_tests() {
+        let data = load_data().unwrap();
+        assert_eq!(data.tests.len(), 10);
+        for test in &data.tests {
+            assert_eq!(test.x.words.len(), test.y.words.len());
+            assert!(!test.a.words.is_empty() && !test.b.words.is_empty());
+        }
+
+        let sentences = stimuli(&word_set(&["rose"]), EmbeddingAssociationTestKind::Sentence, &data.sentence_templates);
+        assert_eq!(sentences.len(), data.sentence_templates.len());
+        assert!(sentences.contains(&"This is rose.".to_string()));
+    }
+
+    #[test]
+    fn test_association_test() {
+        let vectors: Vec<(&str, Vec<f32>)> = vec![
+            ("love", vec![1.0, 0.0]),
+            ("hate", vec![0.0, 1.0]),
+            ("rose", vec![1.0, 0.1]),
+            ("tulip", vec![0.9, 0.2]),
+            ("ant", vec![0.1, 1.0]),
+            ("moth", vec![0.2, 0.9]),
+        ];
+        let embeddings: HashMap<&str, &[f32]> = vectors.iter().map(|(text, vector)| (*text, vector.as_slice())).collect();
+
+        let result = run_association_test(&test_definition(), EmbeddingAssociationTestKind::Word, &[], &embeddings, 1000, 1);
+        assert!(result.missing.is_empty());
+        assert!(result.test_statistic.unwrap() > 0.0);
+        assert!(result.effect_size.unwrap() > 1.5);
+        // 4 of the 24 orderings keep rose and tulip in X
+        let p_value = result.p_value.unwrap();
+        assert!(p_value > 0.1 && p_value < 0.25);
+
+        // Sets without embeddings
+        let embeddings: HashMap<&str, &[f32]> = vectors[..3].iter().map(|(text, vector)| (*text, vector.as_slice())).collect();
+        let result = run_association_test(&test_definition(), EmbeddingAssociationTestKind::Word, &[], &embeddings, 1000, 1);
+        assert_eq!(result.x_count, 1);
+        assert_eq!(result.missing, vec!["tulip", "ant", "moth"]);
+        assert_eq!(result.effect_size, None);
+        assert_eq!(result.p_value, None);
+    }
+
+    #[test]
+    fn test_validate_embeddings() {
+        let embedding = |text: &str, vector: Vec<f32>| Embedding { text: text.to_string(), vector };
+
+        assert!(validate_embeddings(&[embedding("a", vec![1.0, 2.0]), embedding("b", vec![0.0, 1.0])], None).is_ok());
+        assert!(validate_embeddings(&[embedding("a", vec![1.0, 2.0]), embedding("b", vec![1.0])], None).is_err());
+        assert!(validate_embeddings(&[embedding("a", vec![1.0, 2.0])], Some(3)).is_err());
+        assert!(validate_embeddings(&[embedding("a", vec![f32::NAN, 2.0])], None).is_err());
+        assert!(validate_embeddings(&[embedding(" ", vec![1.0])], None).is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use crate::errors::GenericError;
+use crate::metrics_calculation::{calculate_medians, is_privileged_value};
+use crate::types::{
+    get_classifier_model_data, ClassifierPredictor, DataPoint, FeatureAttributionGroupResult,
+    FeatureAttributionResult, FeatureContribution, LogisticRegressionModel, ModelType,
+};
+use crate::utils::is_owner;
+use crate::{check_cycles_before_action, get_model_from_memory, MODELS};
+use std::collections::HashMap;
+
+/// Average contribution `w_j * x_j` of every feature for the privileged and unprivileged groups.
+/// Contributions are sorted by the absolute value of the gap, so the first ones drive the disparity.
+fn group_contributions(
+    data_points: &[&DataPoint],
+    model: &LogisticRegressionModel,
+    sensible_index: usize,
+    threshold: (f64, bool),
+    feature_names: &Option<Vec<String>>,
+) -> (u32, u32, f64, Vec<FeatureContribution>) {
+    let features_len = model.weights.len();
+    let mut privileged_sum = vec![0.0; features_len];
+    let mut unprivileged_sum = vec![0.0; features_len];
+    let mut privileged_count: u32 = 0;
+    let mut unprivileged_count: u32 = 0;
+
+    for point in data_points {
+        let sums = if is_privileged_value(point.features[sensible_index], threshold) {
+            privileged_count += 1;
+            &mut privileged_sum
+        } else {
+            unprivileged_count += 1;
+            &mut unprivileged_sum
+        };
+
+        for (j, (w, x)) in model.weights.iter().zip(point.features.iter()).enumerate() {
+            if !x.is_nan() {
+                sums[j] += w * x;
+            }
+        }
+    }
+
+    let mean = |sum: f64, count: u32| -> f64 {
+        if count == 0 {
+            0.0
+        } else {
+            sum / count as f64
+        }
+    };
+
+    let mut contributions: Vec<FeatureContribution> = (0..features_len)
+        .map(|j| {
+            let privileged_mean = mean(privileged_sum[j], privileged_count);
+            let unprivileged_mean = mean(unprivileged_sum[j], unprivileged_count);
+            FeatureContribution {
+                feature_index: j as u128,
+                feature_name: feature_names.as_ref().and_then(|names| names.get(j).cloned()),
+                weight: model.weights[j],
+                privileged_mean,
+                unprivileged_mean,
+                gap: unprivileged_mean - privileged_mean,
+            }
+        })
+        .collect();
+
+    let score_gap = contributions.iter().map(|c| c.gap).sum();
+
+    contributions.sort_by(|a, b| {
+        b.gap
+            .abs()
+            .partial_cmp(&a.gap.abs())
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(a.feature_index.cmp(&b.feature_index))
+    });
+
+    (privileged_count, unprivileged_count, score_gap, contributions)
+}
+
+/// Calculates per feature contributions to the score of a logistic regression model,
+/// averaged separately for the privileged and unprivileged group of every sensible attribute.
+///
+/// # Parameters
+/// - `data_points`: data points to use.
+/// - `model`: logistic regression weights, aligned with the features.
+/// - `privileged_threshold`: thresholds used to split groups. If None, the middle of the range is used.
+/// - `feature_names`: optional names for the features, used in the result.
+///
+/// # Returns
+/// - One `FeatureAttributionGroupResult` for every sensible attribute, sorted by name.
+pub fn calculate_feature_attribution_groups(
+    data_points: &Vec<DataPoint>,
+    model: &LogisticRegressionModel,
+    privileged_threshold: Option<HashMap<String, (f64, bool)>>,
+    feature_names: &Option<Vec<String>>,
+) -> Result<Vec<FeatureAttributionGroupResult>, String> {
+    if data_points.is_empty() {
+        return Err("There are no data points".to_string());
+    }
+    if data_points
+        .iter()
+        .any(|dp| dp.features.len() != model.weights.len())
+    {
+        return Err(format!(
+            "All data points should have {} features, like the model weights",
+            model.weights.len()
+        ));
+    }
+
+    let thresholds = privileged_threshold.unwrap_or_else(|| calculate_medians(data_points));
+
+    let mut attributes: Vec<(String, usize)> = data_points[0]
+        .privileged_map
+        .iter()
+        .map(|(name, index)| (name.clone(), *index as usize))
+        .collect();
+    attributes.sort();
+
+    let all_points: Vec<&DataPoint> = data_points.iter().collect();
+    // Actual positives, as equal opportunity difference only uses them
+    let positive_points: Vec<&DataPoint> = data_points.iter().filter(|dp| dp.target).collect();
+
+    let results = attributes
+        .into_iter()
+        .map(|(name, index)| {
+            let threshold = *thresholds.get(&name).unwrap_or(&(0.0, true));
+
+            let (privileged_count, unprivileged_count, score_gap, contributions) =
+                group_contributions(&all_points, model, index, threshold, feature_names);
+            let (_, _, positive_target_score_gap, positive_target_contributions) =
+                group_contributions(&positive_points, model, index, threshold, feature_names);
+
+            FeatureAttributionGroupResult {
+                sensible_attribute: name,
+                privileged_count,
+                unprivileged_count,
+                score_gap,
+                contributions,
+                positive_target_score_gap,
+                positive_target_contributions,
+            }
+        })
+        .collect();
+
+    Ok(results)
+}
+
+/// Explains group disparities of a classifier with a logistic regression predictor.
+/// It uses the data points with the latest timestamp and the cached thresholds, if any.
+///
+/// # Parameters
+/// - `model_id: u128`: classifier model id.
+/// - `feature_names: Option<Vec<String>>`: optional names for the features, aligned with the features vector.
+///
+/// # Returns
+/// - `Result<FeatureAttributionResult, GenericError>`: the result, which is also saved in the model.
+#[ic_cdk::update]
+pub fn calculate_feature_attribution(
+    model_id: u128,
+    feature_names: Option<Vec<String>>,
+) -> Result<FeatureAttributionResult, GenericError> {
+    check_cycles_before_action();
+    let caller = ic_cdk::api::caller();
+
+    let model = get_model_from_memory(model_id)?;
+    is_owner(&model, caller);
+
+    let model_data = match &model.model_type {
+        ModelType::Classifier(_) => get_classifier_model_data(&model),
+        _ => {
+            return Err(GenericError::new(
+                GenericError::INVALID_MODEL_TYPE,
+                "Model should be a classifier.",
+            ))
+        }
+    };
+
+    let lr = match &model_data.predictor {
+        Some(ClassifierPredictor::LogisticRegression(lr)) => lr.clone(),
+        _ => {
+            return Err(GenericError::new(
+                GenericError::NOT_FOUND,
+                "Model has no logistic regression predictor set. Use set_classifier_predictor first.",
+            ))
+        }
+    };
+
+    let latest_timestamp = match model_data.data_points.last() {
+        Some(point) => point.timestamp,
+        None => {
+            return Err(GenericError::new(
+                GenericError::EMPTY_INPUT,
+                "Model has no data points.",
+            ))
+        }
+    };
+
+    let data_points: Vec<DataPoint> = model_data
+        .data_points
+        .iter()
+        .filter(|point| point.timestamp == latest_timestamp)
+        .cloned()
+        .collect();
+
+    let thresholds = model
+        .cached_thresholds
+        .as_ref()
+        .and_then(|cached| cached.thresholds.clone());
+
+    let sensible_attributes =
+        calculate_feature_attribution_groups(&data_points, &lr, thresholds, &feature_names)
+            .map_err(|e| GenericError::new(GenericError::INVALID_ARGUMENT, e))?;
+
+    let result = FeatureAttributionResult {
+        sensible_attributes,
+        timestamp: ic_cdk::api::time(),
+    };
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&model_id).expect("Model not found");
+        let mut model_data = get_classifier_model_data(&model);
+        model_data.feature_attribution = Some(result.clone());
+        model.model_type = ModelType::Classifier(model_data);
+        models.insert(model_id, model);
+    });
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_point(features: Vec<f64>, target: bool) -> DataPoint {
+        DataPoint {
+            data_point_id: 0,
+            target,
+            privileged_map: HashMap::from([("gender".to_string(), 0)]),
+            predicted: true,
+            features,
+            timestamp: 1,
+        }
+    }
+
+    #[test]
+    fn test_feature_driving_the_gap_comes_first() {
+        // Privileged group (gender = 1) has higher income
+        let data_points = vec![
+            data_point(vec![1.0, 10.0, 1.0], true),
+            data_point(vec![1.0, 8.0, 1.0], false),
+            data_point(vec![0.0, 2.0, 1.0], true),
+            data_point(vec![0.0, 4.0, 1.0], false),
+        ];
+        let model = LogisticRegressionModel {
+            weights: vec![0.0, 0.5, 1.0],
+            intercept: 0.0,
+            threshold: 0.5,
+        };
+        let names = Some(vec![
+            "gender".to_string(),
+            "income".to_string(),
+            "bias".to_string(),
+        ]);
+
+        let results =
+            calculate_feature_attribution_groups(&data_points, &model, None, &names).unwrap();
+
+        assert_eq!(results.len(), 1);
+        let result = &results[0];
+        assert_eq!(result.privileged_count, 2);
+        assert_eq!(result.unprivileged_count, 2);
+
+        let first = &result.contributions[0];
+        assert_eq!(first.feature_name, Some("income".to_string()));
+        assert_eq!(first.privileged_mean, 4.5);
+        assert_eq!(first.unprivileged_mean, 1.5);
+        assert_eq!(first.gap, -3.0);
+        assert_eq!(result.score_gap, -3.0);
+
+        // Only actual positives: income 10 vs 2
+        assert_eq!(result.positive_target_score_gap, -4.0);
+    }
+
+    #[test]
+    fn test_features_length_should_match_weights() {
+        let data_points = vec![data_point(vec![1.0, 10.0], true)];
+        let model = LogisticRegressionModel {
+            weights: vec![1.0],
+            intercept: 0.0,
+            threshold: 0.5,
+        };
+
+        assert!(calculate_feature_attribution_groups(&data_points, &model, None, &None).is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use crate::types::{ExampleSelectionStrategy, FewShotConfiguration};
+use crate::utils::{seeded_vector_shuffle, select_random_element};
+use std::collections::HashMap;
+
+/// Placeholder replaced by all the examples, numbered, one per line.
+/// Templates with the `<EXAMPLE_0>`..`<EXAMPLE_3>` placeholders support up to 4 examples.
+pub const EXAMPLES_PLACEHOLDER: &str = "<EXAMPLES>";
+
+/// Number of consecutive `<EXAMPLE_i>` placeholders in a template, starting from 0
+pub fn example_placeholders_count(prompt_template: &str) -> usize {
+    (0..)
+        .take_while(|i| prompt_template.contains(&format!("<EXAMPLE_{}>", i)))
+        .count()
+}
+
+/// Checks that a template has room for the number of shots
+pub fn check_shots_fit_template(prompt_template: &str, shots: u32) -> Result<(), String> {
+    if prompt_template.contains(EXAMPLES_PLACEHOLDER) {
+        return Ok(());
+    }
+
+    let placeholders = example_placeholders_count(prompt_template);
+    if shots as usize > placeholders {
+        return Err(format!(
+            "The prompt template has {} example placeholders, but {} shots were requested. Use the {} placeholder for more examples.",
+            placeholders, shots, EXAMPLES_PLACEHOLDER
+        ));
+    }
+
+    Ok(())
+}
+
+/// Removes the lines of a template that contain any of the placeholders
+fn remove_placeholder_lines(prompt: &str, placeholders: &[String]) -> String {
+    prompt
+        .split('\n')
+        .filter(|line| !placeholders.iter().any(|placeholder| line.contains(placeholder.as_str())))
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// Replaces the example placeholders of a template.
+/// Lines with `<EXAMPLE_i>` placeholders that have no example, or the `<EXAMPLES>` line
+/// when there are no examples (zero-shot), are removed.
+pub fn fill_examples(prompt_template: &str, examples: &[String]) -> Result<String, String> {
+    check_shots_fit_template(prompt_template, examples.len() as u32)?;
+
+    if prompt_template.contains(EXAMPLES_PLACEHOLDER) {
+        if examples.is_empty() {
+            return Ok(remove_placeholder_lines(
+                prompt_template,
+                &[EXAMPLES_PLACEHOLDER.to_string()],
+            ));
+        }
+        let examples_block = examples
+            .iter()
+            .enumerate()
+            .map(|(i, example)| format!("{}. {}", i + 1, example))
+            .collect::<Vec<String>>()
+            .join("\n");
+        return Ok(prompt_template.replace(EXAMPLES_PLACEHOLDER, &examples_block));
+    }
+
+    let placeholders = example_placeholders_count(prompt_template);
+
+    let mut prompt = prompt_template.to_string();
+    for (i, example) in examples.iter().enumerate() {
+        prompt = prompt.replace(&format!("<EXAMPLE_{}>", i), example);
+    }
+
+    if examples.len() < placeholders {
+        let unused: Vec<String> = (examples.len()..placeholders)
+            .map(|i| format!("<EXAMPLE_{}>", i))
+            .collect();
+        prompt = remove_placeholder_lines(&prompt, &unused);
+    }
+
+    Ok(prompt)
+}
+
+/// Picks a row from the candidates that haven't been used yet
+fn pick_unused(candidates: &[usize], used: &[usize], seed: u32) -> Option<usize> {
+    select_random_element(
+        candidates.iter().filter(|index| !used.contains(index)).copied(),
+        seed,
+    )
+}
+
+/// Distance between two rows over the shared columns.
+/// Numeric values use the relative difference, other values count 1 if they are different.
+fn row_distance(a: &HashMap<String, String>, b: &HashMap<String, String>, columns: &[&String]) -> f64 {
+    columns
+        .iter()
+        .map(|column| {
+            let (x, y) = match (a.get(*column), b.get(*column)) {
+                (Some(x), Some(y)) => (x.trim(), y.trim()),
+                _ => return 1.0,
+            };
+            match (x.parse::<f64>(), y.parse::<f64>()) {
+                (Ok(x), Ok(y)) => (x - y).abs() / x.abs().max(y.abs()).max(1.0),
+                _ => {
+                    if x == y {
+                        0.0
+                    } else {
+                        1.0
+                    }
+                }
+            }
+        })
+        .sum()
+}
+
+/// Columns of a dataset and the values used to pick examples
+pub struct DatasetColumns<'a> {
+    pub sensible_attribute: &'a str,
+    pub predict_attribute: &'a str,
+    // First two values of the sensible attribute, used by the balanced strategy
+    pub sensible_attribute_values: &'a [&'a str; 2],
+    pub predict_attributes_values: &'a [&'a str; 2],
+    // Columns that are not compared by the nearest neighbour strategy
+    pub ignore_columns: &'a [&'a str],
+}
+
+/// Selects the in-context examples for a test row.
+///
+/// # Parameters
+/// - `records`: train rows.
+/// - `columns`: sensible and predict attributes, their values, and the ignored columns.
+/// - `result`: test row, used by the nearest neighbour and same group strategies.
+/// - `few_shot`: number of examples and strategy.
+///
+/// # Returns
+/// - The selected rows, or an error if there are not enough rows for the strategy.
+pub fn select_examples<'a>(
+    records: &'a [HashMap<String, String>],
+    columns: &DatasetColumns,
+    seed: u32,
+    query_number: usize,
+    result: &HashMap<String, String>,
+    few_shot: &FewShotConfiguration,
+) -> Result<Vec<&'a HashMap<String, String>>, String> {
+    let DatasetColumns {
+        sensible_attribute,
+        predict_attribute,
+        sensible_attribute_values,
+        predict_attributes_values,
+        ignore_columns,
+    } = *columns;
+    let shots = few_shot.shots as usize;
+    let base_seed = seed.wrapping_add(query_number as u32);
+
+    let cell = |sensible_value: &str, predict_value: &str| -> Vec<usize> {
+        records
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| {
+                r.get(sensible_attribute).map(|v| v.as_str()) == Some(sensible_value)
+                    && r.get(predict_attribute).map(|v| v.as_str()) == Some(predict_value)
+            })
+            .map(|(i, _)| i)
+            .collect()
+    };
+
+    // Picks examples cycling over cells, and shuffles them
+    let pick_from_cells = |cells: Vec<(String, &str)>| -> Result<Vec<usize>, String> {
+        let cell_rows: Vec<Vec<usize>> = cells
+            .iter()
+            .map(|(sensible_value, predict_value)| cell(sensible_value, predict_value))
+            .collect();
+        let mut used: Vec<usize> = Vec::with_capacity(shots);
+
+        for i in 0..shots {
+            let k = i % cells.len();
+            let picked = pick_unused(&cell_rows[k], &used, base_seed.wrapping_mul(i as u32 + 1))
+                .ok_or_else(|| {
+                    format!(
+                        "Not enough examples with {} = {} and {} = {}",
+                        sensible_attribute, cells[k].0, predict_attribute, cells[k].1
+                    )
+                })?;
+            used.push(picked);
+        }
+
+        Ok(seeded_vector_shuffle(used, base_seed.wrapping_mul(5)))
+    };
+
+    let indexes: Vec<usize> = match few_shot.strategy {
+        ExampleSelectionStrategy::Balanced => pick_from_cells(vec![
+            (sensible_attribute_values[1].to_string(), predict_attributes_values[1]),
+            (sensible_attribute_values[1].to_string(), predict_attributes_values[0]),
+            (sensible_attribute_values[0].to_string(), predict_attributes_values[1]),
+            (sensible_attribute_values[0].to_string(), predict_attributes_values[0]),
+        ])?,
+        ExampleSelectionStrategy::SameGroup => {
+            let group = result
+                .get(sensible_attribute)
+                .map(|v| v.trim().to_string())
+                .ok_or_else(|| format!("Sensible attribute '{}' not found in row", sensible_attribute))?;
+            pick_from_cells(vec![
+                (group.clone(), predict_attributes_values[1]),
+                (group, predict_attributes_values[0]),
+            ])?
+        }
+        ExampleSelectionStrategy::Random => {
+            let candidates: Vec<usize> = records
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| {
+                    r.get(predict_attribute)
+                        .is_some_and(|v| predict_attributes_values.contains(&v.as_str()))
+                })
+                .map(|(i, _)| i)
+                .collect();
+            if candidates.len() < shots {
+                return Err(format!("Not enough examples for {} shots", shots));
+            }
+            seeded_vector_shuffle(candidates, base_seed.wrapping_mul(7))
+                .into_iter()
+                .take(shots)
+                .collect()
+        }
+        ExampleSelectionStrategy::NearestNeighbour => {
+            let mut compared_columns: Vec<&String> = result
+                .keys()
+                .filter(|key| {
+                    !key.trim().is_empty()
+                        && key.as_str() != sensible_attribute
+                        && key.as_str() != predict_attribute
+                        && !ignore_columns.contains(&key.as_str())
+                })
+                .collect();
+            compared_columns.sort();
+
+            let mut distances: Vec<(f64, usize)> = records
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| {
+                    r.get(predict_attribute)
+                        .is_some_and(|v| predict_attributes_values.contains(&v.as_str()))
+                })
+                .map(|(i, r)| (row_distance(r, result, &compared_columns), i))
+                .collect();
+            if distances.len() < shots {
+                return Err(format!("Not enough examples for {} shots", shots));
+            }
+            distances.sort_by(|a, b| {
+                a.0.partial_cmp(&b.0)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then(a.1.cmp(&b.1))
+            });
+            distances.into_iter().take(shots).map(|(_, i)| i).collect()
+        }
+    };
+
+    Ok(indexes.into_iter().map(|i| &records[i]).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_fairness::PISA_PROMPT;
+
+    fn row(sex: &str, age: &str, label: &str) -> HashMap<String, String> {
+        HashMap::from([
+            ("sex".to_string(), sex.to_string()),
+            ("age".to_string(), age.to_string()),
+            ("label".to_string(), label.to_string()),
+        ])
+    }
+
+    fn records() -> Vec<HashMap<String, String>> {
+        vec![
+            row("0", "20", "L"),
+            row("0", "30", "H"),
+            row("1", "40", "L"),
+            row("1", "50", "H"),
+            row("0", "60", "L"),
+            row("1", "70", "H"),
+        ]
+    }
+
+    fn columns() -> DatasetColumns<'static> {
+        DatasetColumns {
+            sensible_attribute: "sex",
+            predict_attribute: "label",
+            sensible_attribute_values: &["0", "1"],
+            predict_attributes_values: &["L", "H"],
+            ignore_columns: &[],
+        }
+    }
+
+    fn select(few_shot: FewShotConfiguration, result: &HashMap<String, String>) -> Vec<HashMap<String, String>> {
+        let records = records();
+        select_examples(&records, &columns(), 3, 0, result, &few_shot)
+        .unwrap()
+        .into_iter()
+        .cloned()
+        .collect()
+    }
+
+    #[test]
+    fn test_balanced_covers_every_cell() {
+        let examples = select(FewShotConfiguration::default(), &row("0", "35", "H"));
+
+        assert_eq!(examples.len(), 4);
+        for (sex, label) in [("0", "L"), ("0", "H"), ("1", "L"), ("1", "H")] {
+            assert!(examples.iter().any(|e| e["sex"] == sex && e["label"] == label));
+        }
+    }
+
+    #[test]
+    fn test_same_group_only_uses_the_test_row_group() {
+        let few_shot = FewShotConfiguration {
+            shots: 3,
+            strategy: ExampleSelectionStrategy::SameGroup,
+        };
+        let examples = select(few_shot, &row("1", "35", "H"));
+
+        assert_eq!(examples.len(), 3);
+        assert!(examples.iter().all(|e| e["sex"] == "1"));
+    }
+
+    #[test]
+    fn test_nearest_neighbour() {
+        let few_shot = FewShotConfiguration {
+            shots: 2,
+            strategy: ExampleSelectionStrategy::NearestNeighbour,
+        };
+        let examples = select(few_shot, &row("1", "52", "H"));
+
+        assert_eq!(examples[0]["age"], "50");
+        assert_eq!(examples[1]["age"], "60");
+    }
+
+    #[test]
+    fn test_not_enough_examples() {
+        let records = records();
+        let few_shot = FewShotConfiguration {
+            shots: 7,
+            strategy: ExampleSelectionStrategy::Random,
+        };
+        let result = select_examples(&records, &columns(), 3, 0, &row("1", "52", "H"), &few_shot);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_fill_examples() {
+        let template = "Examples:\n1. <EXAMPLE_0>\n2. <EXAMPLE_1>\n3. <EXAMPLE_2>\n4. <EXAMPLE_3>\nRow: *?*";
+
+        assert_eq!(
+            fill_examples(template, &["a".to_string(), "b".to_string()]).unwrap(),
+            "Examples:\n1. a\n2. b\nRow: *?*"
+        );
+        assert_eq!(fill_examples(template, &[]).unwrap(), "Examples:\nRow: *?*");
+        assert!(fill_examples(template, &vec!["a".to_string(); 5]).is_err());
+
+        assert_eq!(
+            fill_examples("<EXAMPLES>\n*?*", &vec!["a".to_string(); 5]).unwrap(),
+            "1. a\n2. a\n3. a\n4. a\n5. a\n*?*"
+        );
+        assert_eq!(fill_examples("Examples: <EXAMPLES>\n*?*", &[]).unwrap(), "*?*");
+    }
+
+    #[test]
+    fn test_fill_pisa_prompt() {
+        let examples: Vec<String> = (0..4).map(|i| format!("row {}", i)).collect();
+
+        let zero_shot = fill_examples(PISA_PROMPT, &[]).unwrap();
+        assert!(!zero_shot.contains("examples"));
+        assert!(!zero_shot.contains("\"\"\""));
+        assert!(zero_shot.contains("JUST ANSWER WITH THE LETTER 'L' OR WITH THE LETTER 'H'.\nA description"));
+
+        let two_shots = fill_examples(PISA_PROMPT, &examples[..2]).unwrap();
+        assert!(two_shots.contains("in the next triple quotes: \"\"\"1. row 0\n2. row 1\"\"\"\nA description"));
+
+        let four_shots = fill_examples(PISA_PROMPT, &examples).unwrap();
+        assert!(four_shots.contains("\"\"\"1. row 0\n2. row 1\n3. row 2\n4. row 3\"\"\"\nA description"));
+
+        for prompt in [zero_shot, two_shots, four_shots] {
+            assert!(!prompt.contains("<EXAMPLE"));
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use crate::config_management::HUGGING_FACE_API_KEY_CONFIG_KEY;
+use crate::errors::GenericError;
+use crate::types::GenerationParameters;
 use crate::CONFIGURATION;
 use serde::{Deserialize, Serialize};
 
@@ -95,6 +97,63 @@ fn transform_hf_response(raw: TransformArgs) -> HttpResponse {
     res
 }
 
+/// Returns the inference provider configured for a model. Unknown providers use the Hugging Face API.
+fn get_inference_provider(inference_provider: &Option<String>) -> Box<dyn InferenceProvider> {
+    match inference_provider.as_deref() {
+        Some("novita") => Box::new(NovitaProvider {}),
+        Some("togetherai") => Box::new(TogetherAIProvider {}),
+        Some("nebius") => Box::new(NebiusProvider {}),
+        _ => Box::new(NoneProvider {}),
+    }
+}
+
+/// Returns the generation parameters an evaluation will use, so they can be stored with the results.
+///
+/// # Parameters
+/// - `inference_provider: &Option<String>`: model inference provider.
+/// - `evaluation_defaults: GenerationParameters`: defaults of the evaluation type. Providers can replace them with their own.
+/// - `overrides: Option<GenerationParameters>`: parameters passed by the caller. Fields that are set replace the defaults.
+///
+/// # Returns
+/// - `Result<GenerationParameters, GenericError>`: the effective parameters, or an error if an override is not valid.
+pub fn resolve_generation_parameters(
+    inference_provider: &Option<String>,
+    evaluation_defaults: GenerationParameters,
+    overrides: Option<GenerationParameters>,
+) -> Result<GenerationParameters, GenericError> {
+    let defaults = get_inference_provider(inference_provider)
+        .default_generation_parameters(evaluation_defaults);
+    let overrides = overrides.unwrap_or_default();
+
+    if overrides.max_new_tokens == Some(0) {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            "max_new_tokens should be greater than 0.",
+        ));
+    }
+    if overrides
+        .temperature
+        .is_some_and(|temperature| !temperature.is_finite() || temperature < 0.0)
+    {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            "temperature should be a non negative number.",
+        ));
+    }
+
+    Ok(GenerationParameters {
+        max_new_tokens: overrides.max_new_tokens.or(defaults.max_new_tokens),
+        temperature: overrides.temperature.or(defaults.temperature),
+        stop: overrides.stop.or(defaults.stop),
+        do_sample: overrides.do_sample.or(defaults.do_sample),
+    })
+}
+
+/// Returns true if the inference provider can receive a system message, see `call_hugging_face_with_system_prompt`
+pub fn supports_system_prompt(inference_provider: &Option<String>) -> bool {
+    get_inference_provider(inference_provider).supports_system_prompt()
+}
+
 /// Calls Hugging Face, returning the HF response.
 ///
 /// # Parameters
@@ -111,6 +170,30 @@ pub async fn call_hugging_face(
     seed: u32,
     hf_parameters: Option<HuggingFaceRequestParameters>,
     inference_provider: &Option<String>,
+) -> Result<String, String> {
+    call_inference_provider(None, input_text, llm_model, seed, hf_parameters, inference_provider).await
+}
+
+/// Like `call_hugging_face`, but sends `system_prompt` as a system message before the input.
+/// Fails if the inference provider doesn't support system prompts.
+pub async fn call_hugging_face_with_system_prompt(
+    system_prompt: String,
+    input_text: String,
+    llm_model: String,
+    seed: u32,
+    hf_parameters: Option<HuggingFaceRequestParameters>,
+    inference_provider: &Option<String>,
+) -> Result<String, String> {
+    call_inference_provider(Some(system_prompt), input_text, llm_model, seed, hf_parameters, inference_provider).await
+}
+
+async fn call_inference_provider(
+    system_prompt: Option<String>,
+    input_text: String,
+    llm_model: String,
+    seed: u32,
+    hf_parameters: Option<HuggingFaceRequestParameters>,
+    inference_provider: &Option<String>,
 ) -> Result<String, String> {
     let hugging_face_bearer_token = CONFIGURATION.with(|config| {
         let config_tree = config.borrow();
@@ -126,7 +209,7 @@ pub async fn call_hugging_face(
 
     let default_parameters = HuggingFaceRequestParameters {
         max_new_tokens: Some(100),
-        stop: Some(vec!['1', '2', '3']),
+        stop: Some(vec!["1".to_string(), "2".to_string(), "3".to_string()]),
         temperature: Some(0.3),
         decoder_input_details: Some(false),
         details: Some(false),
@@ -141,25 +224,18 @@ pub async fn call_hugging_face(
         parameters = p;
     }
 
-    let provider: Box<dyn InferenceProvider> = if let Some(_provider) = inference_provider.clone() {
+    if let Some(_provider) = inference_provider {
         ic_cdk::println!("configured provider: {}", _provider);
-        match _provider.as_str() {
-            "novita" => Box::new(NovitaProvider {}),
-            "togetherai" => Box::new(TogetherAIProvider {}),
-            "nebius" => Box::new(NebiusProvider {}),
-            p => {
-                ic_cdk::println!("No known provider {}, using NoneProvider", p);
-                Box::new(NoneProvider {})
-            }
-        }
-    } else {
-        Box::new(NoneProvider {})
-    };
+    }
+    let provider = get_inference_provider(inference_provider);
 
     ic_cdk::println!("Using {} provider", provider.name());
 
     // 1) Generate payload
-    let json_payload = provider.generate_payload(llm_model.clone(), input_text, parameters)?;
+    let json_payload = match system_prompt {
+        Some(system_prompt) => provider.generate_payload_with_system_prompt(llm_model.clone(), system_prompt, input_text, parameters)?,
+        None => provider.generate_payload(llm_model.clone(), input_text, parameters)?,
+    };
 
     // ic_cdk::println!("{}", String::from_utf8(json_payload.clone()).unwrap());
 
@@ -231,3 +307,92 @@ pub async fn call_hugging_face(
 
     return provider.get_response_text(&response.body);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fairness_defaults() -> GenerationParameters {
+        GenerationParameters {
+            max_new_tokens: Some(2),
+            temperature: Some(0.3),
+            stop: Some(vec!["H".to_string(), "L".to_string()]),
+            do_sample: Some(false),
+        }
+    }
+
+    #[test]
+    fn test_resolve_generation_parameters() {
+        assert_eq!(
+            resolve_generation_parameters(&None, fairness_defaults(), None),
+            Ok(fairness_defaults())
+        );
+
+        let overrides = GenerationParameters {
+            temperature: Some(0.7),
+            ..Default::default()
+        };
+        let parameters =
+            resolve_generation_parameters(&None, fairness_defaults(), Some(overrides)).unwrap();
+        assert_eq!(parameters.temperature, Some(0.7));
+        assert_eq!(parameters.max_new_tokens, Some(2));
+    }
+
+    #[test]
+    fn test_provider_defaults_are_overridable() {
+        let provider = Some("nebius".to_string());
+
+        let parameters = resolve_generation_parameters(&provider, fairness_defaults(), None).unwrap();
+        assert_eq!(parameters.max_new_tokens, Some(5000));
+        assert_eq!(parameters.temperature, Some(0.0));
+        assert_eq!(parameters.stop, None);
+
+        let overrides = GenerationParameters {
+            max_new_tokens: Some(10),
+            ..Default::default()
+        };
+        let parameters =
+            resolve_generation_parameters(&provider, fairness_defaults(), Some(overrides)).unwrap();
+        assert_eq!(parameters.max_new_tokens, Some(10));
+    }
+
+    #[test]
+    fn test_invalid_generation_parameters() {
+        let overrides = GenerationParameters {
+            temperature: Some(-1.0),
+            ..Default::default()
+        };
+        let err = resolve_generation_parameters(&None, fairness_defaults(), Some(overrides)).unwrap_err();
+        assert_eq!(err.code, GenericError::INVALID_ARGUMENT);
+    }
+
+    #[test]
+    fn test_system_prompt_is_a_system_message() {
+        let parameters = HuggingFaceRequestParameters::from_generation_parameters(&fairness_defaults(), 1);
+        let provider = get_inference_provider(&Some("togetherai".to_string()));
+        let payload = provider
+            .generate_payload_with_system_prompt(
+                "model".to_string(),
+                "I am old.".to_string(),
+                "Question".to_string(),
+                parameters.clone(),
+            )
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(payload["messages"][0]["role"], "system");
+        assert_eq!(payload["messages"][0]["content"], "I am old.");
+        assert_eq!(payload["messages"][1]["role"], "user");
+        assert_eq!(payload["messages"][1]["content"], "Question");
+
+        // The Hugging Face text generation API has no roles
+        assert!(!supports_system_prompt(&None));
+        assert!(get_inference_provider(&None)
+            .generate_payload_with_system_prompt(
+                "model".to_string(),
+                "I am old.".to_string(),
+                "Question".to_string(),
+                parameters,
+            )
+            .is_err());
+    }
+}
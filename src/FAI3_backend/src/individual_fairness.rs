@@ -0,0 +1,326 @@
+use crate::errors::GenericError;
+use crate::types::{
+    get_classifier_model_data, DataPoint, FeatureScaling, IndividualFairnessDataPoint,
+    IndividualFairnessResult, ModelType,
+};
+use crate::utils::is_owner;
+use crate::{check_cycles_before_action, get_model_from_memory, MODELS};
+use std::collections::HashSet;
+
+/// Scales the feature columns used for distances.
+/// Columns with a single value are set to 0 when scaling, so they don't affect distances.
+fn scale_features(rows: &[Vec<f64>], scaling: &FeatureScaling) -> Vec<Vec<f64>> {
+    if rows.is_empty() || *scaling == FeatureScaling::None {
+        return rows.to_vec();
+    }
+
+    let columns = rows[0].len();
+    let mut scaled = rows.to_vec();
+
+    for column in 0..columns {
+        let values: Vec<f64> = rows
+            .iter()
+            .map(|row| row[column])
+            .filter(|value| !value.is_nan())
+            .collect();
+        if values.is_empty() {
+            continue;
+        }
+
+        let (offset, scale) = match scaling {
+            FeatureScaling::MinMax => {
+                let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+                let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                (min, max - min)
+            }
+            FeatureScaling::Standard => {
+                let mean = values.iter().sum::<f64>() / values.len() as f64;
+                let variance =
+                    values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+                (mean, variance.sqrt())
+            }
+            FeatureScaling::None => (0.0, 1.0),
+        };
+
+        for row in scaled.iter_mut() {
+            row[column] = if scale == 0.0 {
+                0.0
+            } else {
+                (row[column] - offset) / scale
+            };
+        }
+    }
+
+    scaled
+}
+
+/// Euclidean distance. Features that are NaN in any of the rows are ignored.
+fn distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter()
+        .zip(b.iter())
+        .filter(|(x, y)| !x.is_nan() && !y.is_nan())
+        .map(|(x, y)| (x - y).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Calculates the k-nearest neighbours consistency score.
+/// For every data point, consistency is `1 - |prediction - mean prediction of its k nearest neighbours|`.
+/// Sensible features (the ones in privileged_map) are excluded from the distance.
+///
+/// # Parameters
+/// - `data_points`: data points to use. Features and predictions are used.
+/// - `k`: number of neighbours.
+/// - `scaling`: scaling applied to every feature before calculating distances.
+/// - `top`: number of most inconsistent data points to return.
+///
+/// # Returns
+/// - Tuple with overall consistency, the excluded feature indexes and the `top` most inconsistent data points.
+pub fn calculate_consistency(
+    data_points: &[DataPoint],
+    k: usize,
+    scaling: &FeatureScaling,
+    top: usize,
+) -> Result<(f32, Vec<u128>, Vec<IndividualFairnessDataPoint>), String> {
+    if k == 0 {
+        return Err("k should be higher than zero".to_string());
+    }
+    if data_points.len() <= k {
+        return Err(format!(
+            "At least {} data points are required for k = {}",
+            k + 1,
+            k
+        ));
+    }
+
+    let features_len = data_points[0].features.len();
+    if data_points.iter().any(|dp| dp.features.len() != features_len) {
+        return Err("All data points should have the same number of features".to_string());
+    }
+
+    let excluded: HashSet<usize> = data_points
+        .iter()
+        .flat_map(|dp| dp.privileged_map.values().map(|index| *index as usize))
+        .collect();
+
+    let rows: Vec<Vec<f64>> = data_points
+        .iter()
+        .map(|dp| {
+            dp.features
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| !excluded.contains(index))
+                .map(|(_, value)| *value)
+                .collect()
+        })
+        .collect();
+
+    if rows[0].is_empty() {
+        return Err("There are no features left after excluding the sensible ones".to_string());
+    }
+
+    let rows = scale_features(&rows, scaling);
+
+    let mut results: Vec<IndividualFairnessDataPoint> = Vec::with_capacity(data_points.len());
+
+    for (i, point) in data_points.iter().enumerate() {
+        let mut distances: Vec<(f64, usize)> = rows
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(j, row)| (distance(&rows[i], row), j))
+            .collect();
+        let by_distance = |a: &(f64, usize), b: &(f64, usize)| {
+            a.0.partial_cmp(&b.0)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.1.cmp(&b.1))
+        };
+        // Only the k nearest are needed, so they are selected in linear time and then sorted
+        distances.select_nth_unstable_by(k - 1, by_distance);
+        distances.truncate(k);
+        distances.sort_by(by_distance);
+
+        let neighbours: Vec<usize> = distances.iter().take(k).map(|(_, j)| *j).collect();
+        let positive_neighbours = neighbours
+            .iter()
+            .filter(|j| data_points[**j].predicted)
+            .count();
+        let mean_prediction = positive_neighbours as f64 / k as f64;
+        let prediction = if point.predicted { 1.0 } else { 0.0 };
+
+        results.push(IndividualFairnessDataPoint {
+            data_point_id: point.data_point_id,
+            predicted: point.predicted,
+            consistency: (1.0 - (prediction - mean_prediction).abs()) as f32,
+            neighbours: neighbours
+                .iter()
+                .map(|j| data_points[*j].data_point_id)
+                .collect(),
+        });
+    }
+
+    let overall =
+        results.iter().map(|r| r.consistency).sum::<f32>() / results.len() as f32;
+
+    results.sort_by(|a, b| {
+        a.consistency
+            .partial_cmp(&b.consistency)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(a.data_point_id.cmp(&b.data_point_id))
+    });
+    results.truncate(top);
+
+    let mut excluded: Vec<u128> = excluded.into_iter().map(|i| i as u128).collect();
+    excluded.sort();
+
+    Ok((overall, excluded, results))
+}
+
+/// Calculates the individual fairness consistency score for a classifier model.
+/// It uses the data points with the latest timestamp.
+///
+/// # Parameters
+/// - `model_id: u128`: classifier model id.
+/// - `k: u32`: number of neighbours.
+/// - `scaling: FeatureScaling`: feature scaling used for distances.
+/// - `top: u32`: number of most inconsistent data points returned.
+///
+/// # Returns
+/// - `Result<IndividualFairnessResult, GenericError>`: the result, which is also saved in the model.
+#[ic_cdk::update]
+pub fn calculate_individual_fairness(
+    model_id: u128,
+    k: u32,
+    scaling: FeatureScaling,
+    top: u32,
+) -> Result<IndividualFairnessResult, GenericError> {
+    check_cycles_before_action();
+    let caller = ic_cdk::api::caller();
+
+    let model = get_model_from_memory(model_id)?;
+    is_owner(&model, caller);
+
+    let model_data = match &model.model_type {
+        ModelType::Classifier(_) => get_classifier_model_data(&model),
+        _ => {
+            return Err(GenericError::new(
+                GenericError::INVALID_MODEL_TYPE,
+                "Model should be a classifier.",
+            ))
+        }
+    };
+
+    let latest_timestamp = match model_data.data_points.last() {
+        Some(point) => point.timestamp,
+        None => {
+            return Err(GenericError::new(
+                GenericError::EMPTY_INPUT,
+                "Model has no data points.",
+            ))
+        }
+    };
+
+    let data_points: Vec<DataPoint> = model_data
+        .data_points
+        .iter()
+        .filter(|point| point.timestamp == latest_timestamp)
+        .cloned()
+        .collect();
+
+    let (consistency_overall, excluded_features, most_inconsistent) =
+        calculate_consistency(&data_points, k as usize, &scaling, top as usize)
+            .map_err(|e| GenericError::new(GenericError::INVALID_ARGUMENT, e))?;
+
+    let result = IndividualFairnessResult {
+        consistency_overall,
+        k,
+        scaling,
+        excluded_features,
+        total_data_points: data_points.len() as u32,
+        most_inconsistent,
+        timestamp: ic_cdk::api::time(),
+    };
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&model_id).expect("Model not found");
+        let mut model_data = get_classifier_model_data(&model);
+        model_data.individual_fairness = Some(result.clone());
+        model.model_type = ModelType::Classifier(model_data);
+        models.insert(model_id, model);
+    });
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn data_point(id: u128, features: Vec<f64>, predicted: bool) -> DataPoint {
+        DataPoint {
+            data_point_id: id,
+            target: true,
+            privileged_map: HashMap::from([("gender".to_string(), 0)]),
+            predicted,
+            features,
+            timestamp: 1,
+        }
+    }
+
+    #[test]
+    fn test_consistent_clusters() {
+        let data_points = vec![
+            data_point(1, vec![0.0, 1.0], true),
+            data_point(2, vec![1.0, 1.1], true),
+            data_point(3, vec![0.0, 10.0], false),
+            data_point(4, vec![1.0, 10.1], false),
+        ];
+
+        let (overall, excluded, top) =
+            calculate_consistency(&data_points, 1, &FeatureScaling::None, 2).unwrap();
+
+        assert_eq!(overall, 1.0);
+        assert_eq!(excluded, vec![0]);
+        assert_eq!(top.len(), 2);
+    }
+
+    #[test]
+    fn test_sensible_feature_is_excluded_from_distance() {
+        // Points 1 and 2 only differ in the sensible feature, but get different outcomes
+        let data_points = vec![
+            data_point(1, vec![0.0, 5.0], true),
+            data_point(2, vec![1.0, 5.0], false),
+            data_point(3, vec![0.0, 100.0], true),
+        ];
+
+        let (_, _, top) =
+            calculate_consistency(&data_points, 1, &FeatureScaling::None, 1).unwrap();
+
+        assert_eq!(top[0].data_point_id, 1);
+        assert_eq!(top[0].consistency, 0.0);
+        assert_eq!(top[0].neighbours, vec![2]);
+    }
+
+    #[test]
+    fn test_scaling() {
+        let rows = vec![vec![0.0, 5.0], vec![10.0, 5.0]];
+
+        assert_eq!(
+            scale_features(&rows, &FeatureScaling::MinMax),
+            vec![vec![0.0, 0.0], vec![1.0, 0.0]]
+        );
+        assert_eq!(
+            scale_features(&rows, &FeatureScaling::Standard),
+            vec![vec![-1.0, 0.0], vec![1.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn test_not_enough_data_points() {
+        let data_points = vec![data_point(1, vec![0.0, 1.0], true)];
+
+        assert!(calculate_consistency(&data_points, 1, &FeatureScaling::None, 1).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::types::GenerationParameters;
+
 pub const HUGGING_FACE_ENDPOINT: &str = "https://api-inference.huggingface.co/models";
 pub const HUGGING_FACE_INFERENCE_PROVIDER_URL: &str = "https://router.huggingface.co";
 
@@ -13,6 +15,8 @@ pub struct OpenAIRequest {
     pub do_sample: Option<bool>,
     pub stream: bool,
     pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -24,7 +28,7 @@ pub struct OpenAIMessage {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct HuggingFaceRequestParameters {
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub stop: Option<Vec<char>>,
+    pub stop: Option<Vec<String>>,
     pub max_new_tokens: Option<u32>,
     pub temperature: Option<f32>,
     pub return_full_text: Option<bool>,
@@ -34,6 +38,60 @@ pub struct HuggingFaceRequestParameters {
     pub do_sample: Option<bool>,
 }
 
+impl HuggingFaceRequestParameters {
+    /// Request parameters for the generation parameters of an evaluation
+    pub fn from_generation_parameters(parameters: &GenerationParameters, seed: u32) -> Self {
+        Self {
+            stop: parameters.stop.clone(),
+            max_new_tokens: parameters.max_new_tokens,
+            temperature: parameters.temperature,
+            return_full_text: Some(false),
+            decoder_input_details: Some(false),
+            details: Some(false),
+            seed: Some(seed),
+            do_sample: parameters.do_sample,
+        }
+    }
+}
+
+/// Defaults of the OpenAI compatible providers. Chat models can reason before answering,
+/// so they get a large token budget and no stop tokens.
+pub fn openai_default_generation_parameters() -> GenerationParameters {
+    GenerationParameters {
+        max_new_tokens: Some(5000),
+        temperature: Some(0.0),
+        stop: None,
+        do_sample: Some(false),
+    }
+}
+
+/// OpenAI compatible request, honoring the generation parameters.
+/// The system prompt, if any, is sent as a system message before the user input.
+pub fn openai_request(llm_model: String, system_prompt: Option<String>, input_text: String, parameters: HuggingFaceRequestParameters) -> OpenAIRequest {
+    let mut messages = Vec::new();
+    if let Some(system_prompt) = system_prompt {
+        messages.push(OpenAIMessage {
+            role: "system".to_string(),
+            content: system_prompt,
+        });
+    }
+    messages.push(OpenAIMessage {
+        role: "user".to_string(),
+        content: input_text,
+    });
+
+    OpenAIRequest {
+        model: llm_model,
+        messages,
+        stream: false,
+        max_tokens: parameters.max_new_tokens,
+        seed: parameters.seed,
+        do_sample: parameters.do_sample,
+        temperature: parameters.temperature,
+        stop: parameters.stop,
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct HuggingFaceRequest {
     pub inputs: String,
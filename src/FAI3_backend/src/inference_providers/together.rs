@@ -6,9 +6,10 @@ use super::traits::InferenceProvider;
 use super::lib::{
     HuggingFaceRequestParameters,
     HUGGING_FACE_INFERENCE_PROVIDER_URL,
-    OpenAIRequest,
-    OpenAIMessage,
+    openai_default_generation_parameters,
+    openai_request,
 };
+use crate::types::GenerationParameters;
 
 // TogetherAI JSON response
 #[derive(Serialize, Deserialize)]
@@ -49,26 +50,27 @@ impl InferenceProvider for TogetherAIProvider {
     fn name(&self) -> &str {
         return "togetherai";
     }
+
+    fn default_generation_parameters(&self, _evaluation_defaults: GenerationParameters) -> GenerationParameters {
+        openai_default_generation_parameters()
+    }
     
     fn generate_payload(&self, llm_model: String, input_text: String, parameters: HuggingFaceRequestParameters) -> Result<Vec<u8>, String> {
-        let payload = OpenAIRequest {
-            model: llm_model.to_lowercase(),
-            messages: vec![
-                OpenAIMessage {
-                    role: "user".to_string(),
-                    content: input_text,
-                }
-            ],
-            stream: false,
-            max_tokens: Some(5000),
-            seed: parameters.seed,
-            do_sample: Some(false),
-            temperature: Some(0.0),
-        };
+        let payload = openai_request(llm_model.to_lowercase(), None, input_text, parameters);
 
         return serde_json::to_vec(&payload).map_err(|e| format!("Failed to serialize payload: {}", e));
     }
 
+    fn supports_system_prompt(&self) -> bool {
+        true
+    }
+
+    fn generate_payload_with_system_prompt(&self, llm_model: String, system_prompt: String, input_text: String, parameters: HuggingFaceRequestParameters) -> Result<Vec<u8>, String> {
+        let payload = openai_request(llm_model.to_lowercase(), Some(system_prompt), input_text, parameters);
+
+        serde_json::to_vec(&payload).map_err(|e| format!("Failed to serialize payload: {}", e))
+    }
+
     fn get_response_text(&self, response_body: &Vec<u8>) -> Result<String, String> {
         // Parse raw bytes into TogetherAI response format
         let json_val: serde_json::Value =
@@ -1,8 +1,20 @@
 use super::lib::HuggingFaceRequestParameters;
+use crate::types::GenerationParameters;
 
 pub trait InferenceProvider {
     fn name(&self) -> &str;
+    /// Generation parameters used when the caller doesn't override them
+    fn default_generation_parameters(&self, evaluation_defaults: GenerationParameters) -> GenerationParameters {
+        evaluation_defaults
+    }
     fn generate_payload(&self, llm_model: String, input_text: String, parameters: HuggingFaceRequestParameters) -> Result<Vec<u8>, String>;
+    /// Whether the API has message roles, so a system message can be sent before the input
+    fn supports_system_prompt(&self) -> bool {
+        false
+    }
+    fn generate_payload_with_system_prompt(&self, _llm_model: String, _system_prompt: String, _input_text: String, _parameters: HuggingFaceRequestParameters) -> Result<Vec<u8>, String> {
+        Err(format!("The {} provider does not support system prompts", self.name()))
+    }
     fn get_response_text(&self, response_body: &Vec<u8>) -> Result<String, String>;
     fn endpoint_url(&self, llm_model: String) -> String;
 }
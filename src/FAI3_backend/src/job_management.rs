@@ -12,6 +12,8 @@ pub const JOB_STATUS_STOPPED: &str = "Stopped";
 pub const JOB_STATUS_PAUSED: &str = "Paused";
 
 const QUEUE_CYCLE_THRESHOLD: u64 = 40_000_000_000;
+// Stored answers re-scored in every step of a rescoring job
+pub const RESCORING_BATCH_SIZE: usize = 100;
 
 thread_local! {
     static QUEUE_BUSY: RefCell<bool> = RefCell::new(false);
@@ -404,6 +406,36 @@ pub async fn process_job_queue() {
         JobType::LanguageEvaluation { language_model_evaluation_id } => {
             crate::llm_language_evaluations::process_next_query(job.model_id, language_model_evaluation_id, &job).await
         },
+        JobType::RemoteClassifierEvaluation { remote_evaluation_id } => {
+            crate::remote_classifier::process_next_remote_classifier_query(job.model_id, remote_evaluation_id, &job).await
+        },
+        JobType::MultiSeedAggregation { multi_seed_evaluation_id, ref job_dependencies } => {
+            crate::multi_seed::process_multi_seed_aggregation_from_job(&job, multi_seed_evaluation_id, job_dependencies.clone())
+        },
+        JobType::PersonaBias { persona_bias_evaluation_id } => {
+            crate::llm_persona_bias::persona_bias_process_next_query(job.model_id, persona_bias_evaluation_id, &job).await
+        },
+        JobType::BBQ { bbq_evaluation_id } => {
+            crate::bbq::bbq_process_next_query(job.model_id, bbq_evaluation_id, &job).await
+        },
+        JobType::CrowsPairs { crows_pairs_test_id } => {
+            crate::crows_pairs::crows_pairs_process_next_query(job.model_id, crows_pairs_test_id, &job).await
+        },
+        JobType::WinoBias { winobias_evaluation_id } => {
+            crate::winobias::winobias_process_next_query(job.model_id, winobias_evaluation_id, &job).await
+        },
+        JobType::OpenGeneration { open_generation_evaluation_id } => {
+            crate::open_generation::open_generation_process_next_query(job.model_id, open_generation_evaluation_id, &job).await
+        },
+        JobType::PromptRobustnessAggregation { prompt_robustness_evaluation_id, ref job_dependencies } => {
+            crate::prompt_robustness::process_prompt_robustness_aggregation_from_job(&job, prompt_robustness_evaluation_id, job_dependencies.clone())
+        },
+        JobType::LLMFairnessRescoring { model_evaluation_id } => {
+            crate::llm_fairness::llm_fairness_rescore_next_batch(job.model_id, model_evaluation_id, &job)
+        },
+        JobType::ContextAssociationTestRescoring { metrics_bag_id } => {
+            crate::context_association_test::context_association_test_rescore_next_batch(job.model_id, metrics_bag_id, &job)
+        },
         _ => {
             ic_cdk::println!("Job type not supported yet. Ignoring it.");
             Ok(true)
@@ -1,18 +1,40 @@
 mod admin_management;
+mod answer_extraction;
+mod bbq;
+mod classifier_counter_factual;
+mod classifier_predictor;
 mod config_management;
+mod crows_pairs;
 pub mod context_association_test;
 pub(crate) mod cycles_management;
 mod data_management;
+mod embedding_association;
 pub mod errors;
+mod feature_attribution;
+mod few_shot;
 mod hugging_face;
+mod individual_fairness;
 pub mod inference_providers;
 mod job_management;
 pub mod llm_fairness;
+mod llm_fairness_datasets;
+mod llm_fairness_templates;
 pub mod llm_language_evaluations;
+mod llm_persona_bias;
 mod metrics_calculation;
 mod model;
+mod multi_seed;
+mod open_generation;
+mod prompt_robustness;
+mod query_jobs;
+mod refusals;
+mod remote_classifier;
+mod sensible_attributes;
+mod shared_datasets;
+mod test_sampling;
 pub mod types;
 mod utils;
+mod winobias;
 
 use candid::Principal;
 use errors::GenericError;
@@ -26,7 +48,10 @@ use std::cell::RefCell;
 
 use admin_management::only_admin;
 use cycles_management::check_cycles_before_action;
-use types::{AverageMetrics, DataPoint, Job, Metrics, Model, ModelDetails};
+use types::{
+    AverageMetrics, DataPoint, Job, LLMFairnessDatasetDefinition, LLMFairnessPromptTemplate, Metrics,
+    Model, ModelDetails, RefusalDetectorConfig, SharedDataset,
+};
 use utils::is_owner;
 
 // thread_local! {
@@ -121,6 +146,94 @@ thread_local! {
             0
         ).unwrap()
     );
+
+    static NEXT_REMOTE_CLASSIFIER_EVALUATION_ID: RefCell<Cell<u128, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+            1
+        ).unwrap()
+    );
+
+    static DATASETS: RefCell<StableBTreeMap<u128, SharedDataset, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+        )
+    );
+
+    static NEXT_DATASET_ID: RefCell<Cell<u128, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+            1
+        ).unwrap()
+    );
+
+    static CUSTOM_LLM_FAIRNESS_DATASETS: RefCell<StableBTreeMap<String, LLMFairnessDatasetDefinition, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+        )
+    );
+
+    static LLM_FAIRNESS_PROMPT_TEMPLATES: RefCell<StableBTreeMap<String, LLMFairnessPromptTemplate, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+        )
+    );
+
+    static NEXT_MULTI_SEED_EVALUATION_ID: RefCell<Cell<u128, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+            1
+        ).unwrap()
+    );
+
+    static NEXT_PERSONA_BIAS_EVALUATION_ID: RefCell<Cell<u128, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+            1
+        ).unwrap()
+    );
+
+    static NEXT_BBQ_EVALUATION_ID: RefCell<Cell<u128, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+            1
+        ).unwrap()
+    );
+
+    static NEXT_CROWS_PAIRS_TEST_ID: RefCell<Cell<u128, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+            1
+        ).unwrap()
+    );
+
+    static NEXT_WINOBIAS_EVALUATION_ID: RefCell<Cell<u128, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+            1
+        ).unwrap()
+    );
+
+    static REFUSAL_DETECTOR_CONFIG: RefCell<Cell<RefusalDetectorConfig, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+            RefusalDetectorConfig::default()
+        ).unwrap()
+    );
+
+    static NEXT_OPEN_GENERATION_EVALUATION_ID: RefCell<Cell<u128, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+            1
+        ).unwrap()
+    );
+
+    static NEXT_PROMPT_ROBUSTNESS_EVALUATION_ID: RefCell<Cell<u128, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+            1
+        ).unwrap()
+    );
 }
 
 #[ic_cdk::init]
@@ -0,0 +1,372 @@
+use crate::admin_management::only_admin;
+use crate::answer_extraction::validate_answer_extraction_strategy;
+use crate::errors::GenericError;
+use crate::few_shot::EXAMPLES_PLACEHOLDER;
+use crate::llm_fairness::{is_builtin_llm_fairness_dataset, LLMFairnessDatasetRef};
+use crate::sensible_attributes::MAX_ADDITIONAL_SENSIBLE_ATTRIBUTES;
+use crate::types::{AdditionalSensibleAttribute, LLMFairnessDatasetDefinition};
+use crate::{check_cycles_before_action, CUSTOM_LLM_FAIRNESS_DATASETS};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+const PROMPT_PLACEHOLDERS: [&str; 5] = [
+    "<EXAMPLE_0>",
+    "<EXAMPLE_1>",
+    "<EXAMPLE_2>",
+    "<EXAMPLE_3>",
+    "*?*",
+];
+
+/// Parses a CSV and checks that it has rows and the required columns
+fn parse_csv(
+    csv_name: &str,
+    content: &str,
+    required_columns: &[&str],
+) -> Result<Vec<HashMap<String, String>>, GenericError> {
+    let mut reader = csv::ReaderBuilder::new().from_reader(content.as_bytes());
+    let records: Vec<HashMap<String, String>> = reader
+        .deserialize()
+        .collect::<Result<Vec<HashMap<String, String>>, _>>()
+        .map_err(|e| {
+            GenericError::new(
+                GenericError::INVALID_FORMAT,
+                format!("Invalid {} CSV: {}", csv_name, e),
+            )
+        })?;
+
+    let first = records.first().ok_or(GenericError::new(
+        GenericError::EMPTY_INPUT,
+        format!("{} CSV should have at least one row.", csv_name),
+    ))?;
+
+    if let Some(column) = required_columns
+        .iter()
+        .find(|column| !first.contains_key(**column))
+    {
+        return Err(GenericError::new(
+            GenericError::INVALID_FORMAT,
+            format!("Column '{}' not found in {} CSV.", column, csv_name),
+        ));
+    }
+
+    Ok(records)
+}
+
+/// Checks that a prompt template contains the examples and row placeholders.
+/// The `<EXAMPLES>` placeholder can be used instead of `<EXAMPLE_0>` to `<EXAMPLE_3>`.
+pub(crate) fn validate_prompt_template(template: &str) -> Result<(), GenericError> {
+    let uses_examples_block = template.contains(EXAMPLES_PLACEHOLDER);
+    if let Some(placeholder) = PROMPT_PLACEHOLDERS
+        .iter()
+        .filter(|placeholder| !uses_examples_block || **placeholder == "*?*")
+        .find(|placeholder| !template.contains(**placeholder))
+    {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            format!("Prompt template should contain the {} placeholder.", placeholder),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the sensible attributes evaluated together with the dataset's main one.
+/// They are checked when a dataset is registered and when an evaluation sets its own.
+pub(crate) fn validate_additional_sensible_attributes(
+    dataset: &LLMFairnessDatasetRef,
+    additional_sensible_attributes: &[AdditionalSensibleAttribute],
+) -> Result<(), GenericError> {
+    if additional_sensible_attributes.len() > MAX_ADDITIONAL_SENSIBLE_ATTRIBUTES {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            format!(
+                "At most {} additional sensible attributes can be evaluated.",
+                MAX_ADDITIONAL_SENSIBLE_ATTRIBUTES
+            ),
+        ));
+    }
+    for (i, attribute) in additional_sensible_attributes.iter().enumerate() {
+        let repeated = attribute.name == dataset.sensible_attribute()
+            || attribute.name == dataset.predict_attribute()
+            || additional_sensible_attributes[..i].iter().any(|a| a.name == attribute.name);
+        if repeated {
+            return Err(GenericError::new(
+                GenericError::INVALID_ARGUMENT,
+                format!("Sensible attribute {} is used more than once.", attribute.name),
+            ));
+        }
+        if attribute.privileged_values.is_empty() {
+            return Err(GenericError::new(
+                GenericError::INVALID_ARGUMENT,
+                format!("Sensible attribute {} should have privileged values.", attribute.name),
+            ));
+        }
+        if attribute.counter_factual_values.len() == 1 {
+            return Err(GenericError::new(
+                GenericError::INVALID_ARGUMENT,
+                format!(
+                    "Sensible attribute {} should have no counter factual values or at least two.",
+                    attribute.name
+                ),
+            ));
+        }
+    }
+
+
+    for (csv_name, content) in [("test", dataset.test_csv()), ("counter factual test", dataset.cf_test_csv())] {
+        let mut reader = csv::ReaderBuilder::new().from_reader(content.as_bytes());
+        let headers = reader.headers().map_err(|e| {
+            GenericError::new(
+                GenericError::INVALID_FORMAT,
+                format!("Invalid {} CSV: {}", csv_name, e),
+            )
+        })?;
+        if let Some(attribute) = additional_sensible_attributes
+            .iter()
+            .find(|attribute| !headers.iter().any(|header| header == attribute.name))
+        {
+            return Err(GenericError::new(
+                GenericError::INVALID_FORMAT,
+                format!("Column '{}' not found in {} CSV.", attribute.name, csv_name),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that a dataset definition can be used to run LLM fairness evaluations
+fn validate_llm_fairness_dataset(dataset: &LLMFairnessDatasetDefinition) -> Result<(), GenericError> {
+    if dataset.name.trim().is_empty() {
+        return Err(GenericError::new(
+            GenericError::EMPTY_INPUT,
+            "Dataset name cannot be empty.",
+        ));
+    }
+
+    validate_prompt_template(&dataset.prompt_template)?;
+    if let Some(answer_extraction) = &dataset.answer_extraction {
+        validate_answer_extraction_strategy(answer_extraction)?;
+    }
+
+    if dataset.sensible_attribute_values.len() < 2 {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            "Sensible attribute should have at least two values.",
+        ));
+    }
+    if dataset.predict_attributes_values.len() != 2 {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            "Predict attribute should have exactly two values.",
+        ));
+    }
+
+    let train_records = parse_csv(
+        "train",
+        &dataset.train_csv,
+        &[&dataset.sensible_attribute, &dataset.predict_attribute],
+    )?;
+
+    // Examples for the prompt are picked from every combination of the first two sensible values and predict values
+    for sensible_value in dataset.sensible_attribute_values.iter().take(2) {
+        for predict_value in dataset.predict_attributes_values.iter() {
+            let found = train_records.iter().any(|record| {
+                record.get(&dataset.sensible_attribute) == Some(sensible_value)
+                    && record.get(&dataset.predict_attribute) == Some(predict_value)
+            });
+            if !found {
+                return Err(GenericError::new(
+                    GenericError::INVALID_ARGUMENT,
+                    format!(
+                        "Train CSV has no rows with {} = {} and {} = {}.",
+                        dataset.sensible_attribute,
+                        sensible_value,
+                        dataset.predict_attribute,
+                        predict_value
+                    ),
+                ));
+            }
+        }
+    }
+
+    let sensible_column = dataset
+        .binarized_sensible_attribute_column
+        .as_deref()
+        .unwrap_or(&dataset.sensible_attribute);
+    let test_columns = [
+        dataset.sensible_attribute.as_str(),
+        dataset.predict_attribute.as_str(),
+        sensible_column,
+    ];
+
+    parse_csv("test", &dataset.test_csv, &test_columns)?;
+    let counter_factual_records = parse_csv("counter factual test", &dataset.cf_test_csv, &test_columns)?;
+    validate_additional_sensible_attributes(
+        &LLMFairnessDatasetRef::Custom(Cow::Borrowed(dataset)),
+        dataset.additional_sensible_attributes.as_deref().unwrap_or_default(),
+    )?;
+
+    // Counter factual prompts are compared by sensible value, so the rows should use the same values as the test CSV
+    if let Some(record) = counter_factual_records.iter().find(|record| {
+        !record
+            .get(&dataset.sensible_attribute)
+            .is_some_and(|value| dataset.sensible_attribute_values.iter().any(|v| v == value.trim()))
+    }) {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            format!(
+                "Counter factual test CSV has a {} value that is not one of the sensible attribute values: {}.",
+                dataset.sensible_attribute,
+                record.get(&dataset.sensible_attribute).cloned().unwrap_or_default()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Registers a LLM fairness dataset, so it can be used in `calculate_llm_metrics`
+/// without upgrading the canister.
+///
+/// # Parameters
+/// - `dataset: LLMFairnessDatasetDefinition`: dataset definition, including the train, test and counter factual test CSVs.
+///
+/// # Returns
+/// - `Result<(), GenericError>`: Ok if the dataset was saved.
+#[ic_cdk::update]
+pub fn add_llm_fairness_dataset(dataset: LLMFairnessDatasetDefinition) -> Result<(), GenericError> {
+    only_admin();
+    check_cycles_before_action();
+
+    validate_llm_fairness_dataset(&dataset)?;
+
+    let exists = is_builtin_llm_fairness_dataset(&dataset.name)
+        || CUSTOM_LLM_FAIRNESS_DATASETS.with(|datasets| datasets.borrow().contains_key(&dataset.name));
+    if exists {
+        return Err(GenericError::new(
+            GenericError::ALREADY_EXISTS,
+            format!("A dataset named {} already exists.", dataset.name),
+        ));
+    }
+
+    CUSTOM_LLM_FAIRNESS_DATASETS.with(|datasets| {
+        datasets.borrow_mut().insert(dataset.name.clone(), dataset);
+    });
+
+    Ok(())
+}
+
+/// Removes a LLM fairness dataset registered at runtime.
+/// Built-in datasets cannot be removed. Evaluations already saved in models are kept.
+#[ic_cdk::update]
+pub fn remove_llm_fairness_dataset(name: String) -> Result<(), GenericError> {
+    only_admin();
+    check_cycles_before_action();
+
+    if is_builtin_llm_fairness_dataset(&name) {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            "Built-in datasets cannot be removed.",
+        ));
+    }
+
+    CUSTOM_LLM_FAIRNESS_DATASETS
+        .with(|datasets| datasets.borrow_mut().remove(&name))
+        .map(|_| ())
+        .ok_or(GenericError::new(
+            GenericError::NOT_FOUND,
+            "Dataset not found",
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dataset() -> LLMFairnessDatasetDefinition {
+        LLMFairnessDatasetDefinition {
+            name: "loans".to_string(),
+            prompt_template: "<EXAMPLE_0> <EXAMPLE_1> <EXAMPLE_2> <EXAMPLE_3> Applicant: *?*"
+                .to_string(),
+            train_csv: ",gender,income,approved\n0,f,10,0\n1,f,20,1\n2,m,10,0\n3,m,20,1\n"
+                .to_string(),
+            test_csv: ",gender,income,approved\n0,f,15,1\n1,m,15,0\n".to_string(),
+            cf_test_csv: ",gender,income,approved\n0,m,15,1\n1,f,15,0\n".to_string(),
+            sensible_attribute: "gender".to_string(),
+            predict_attribute: "approved".to_string(),
+            sensible_attribute_values: vec!["f".to_string(), "m".to_string()],
+            predict_attributes_values: vec!["0".to_string(), "1".to_string()],
+            binarized_sensible_attribute_column: None,
+            dataset_subject_label: "Applicant".to_string(),
+            answer_extraction: None,
+            additional_sensible_attributes: None,
+        }
+    }
+
+    #[test]
+    fn test_valid_dataset() {
+        assert_eq!(validate_llm_fairness_dataset(&dataset()), Ok(()));
+    }
+
+    #[test]
+    fn test_prompt_template_placeholders() {
+        let mut ds = dataset();
+        ds.prompt_template = "<EXAMPLE_0> <EXAMPLE_1> <EXAMPLE_2> <EXAMPLE_3>".to_string();
+
+        let err = validate_llm_fairness_dataset(&ds).unwrap_err();
+        assert_eq!(err.code, GenericError::INVALID_ARGUMENT);
+        assert!(err.message.contains("*?*"));
+    }
+
+    #[test]
+    fn test_missing_columns() {
+        let mut ds = dataset();
+        ds.binarized_sensible_attribute_column = Some("binarized_gender".to_string());
+
+        let err = validate_llm_fairness_dataset(&ds).unwrap_err();
+        assert_eq!(err.code, GenericError::INVALID_FORMAT);
+        assert!(err.message.contains("binarized_gender"));
+    }
+
+    #[test]
+    fn test_additional_sensible_attributes() {
+        let mut ds = dataset();
+        ds.additional_sensible_attributes = Some(vec![AdditionalSensibleAttribute {
+            name: "income".to_string(),
+            privileged_values: vec!["20".to_string()],
+            counter_factual_values: vec!["10".to_string(), "20".to_string()],
+        }]);
+        assert_eq!(validate_llm_fairness_dataset(&ds), Ok(()));
+
+        ds.additional_sensible_attributes.as_mut().unwrap()[0].name = "gender".to_string();
+        assert_eq!(
+            validate_llm_fairness_dataset(&ds).unwrap_err().code,
+            GenericError::INVALID_ARGUMENT
+        );
+
+        ds.additional_sensible_attributes.as_mut().unwrap()[0].name = "age".to_string();
+        assert_eq!(
+            validate_llm_fairness_dataset(&ds).unwrap_err().code,
+            GenericError::INVALID_FORMAT
+        );
+    }
+
+    #[test]
+    fn test_counter_factual_values_should_be_sensible_values() {
+        let mut ds = dataset();
+        ds.cf_test_csv = ",gender,income,approved\n0,1,15,1\n1,0,15,0\n".to_string();
+
+        let err = validate_llm_fairness_dataset(&ds).unwrap_err();
+        assert_eq!(err.code, GenericError::INVALID_ARGUMENT);
+        assert!(err.message.contains("gender"));
+    }
+
+    #[test]
+    fn test_train_examples_for_every_group() {
+        let mut ds = dataset();
+        ds.train_csv = ",gender,income,approved\n0,f,10,0\n1,f,20,1\n2,m,10,0\n".to_string();
+
+        assert!(validate_llm_fairness_dataset(&ds).is_err());
+    }
+}
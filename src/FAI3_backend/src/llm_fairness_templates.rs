@@ -0,0 +1,151 @@
+use crate::admin_management::only_admin;
+use crate::errors::GenericError;
+use crate::llm_fairness_datasets::validate_prompt_template;
+use crate::types::LLMFairnessPromptTemplate;
+use crate::{check_cycles_before_action, LLM_FAIRNESS_PROMPT_TEMPLATES};
+
+fn get_prompt_template_from_memory(name: &str) -> Result<LLMFairnessPromptTemplate, GenericError> {
+    LLM_FAIRNESS_PROMPT_TEMPLATES
+        .with(|templates| templates.borrow().get(&name.to_string()))
+        .ok_or(GenericError::new(
+            GenericError::NOT_FOUND,
+            format!("Prompt template {} not found", name),
+        ))
+}
+
+/// Returns the prompt template to use in a LLM fairness evaluation, and the library name if one was used.
+///
+/// # Parameters
+/// - `default_template`: dataset prompt template, used when no other template is passed.
+/// - `prompt_template`: custom template. It's validated.
+/// - `prompt_template_name`: name of a template in the library.
+pub(crate) fn resolve_prompt_template(
+    default_template: &str,
+    prompt_template: Option<String>,
+    prompt_template_name: Option<String>,
+) -> Result<(String, Option<String>), GenericError> {
+    match (prompt_template, prompt_template_name) {
+        (Some(_), Some(_)) => Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            "Either a custom prompt template or a template name can be passed, not both.",
+        )),
+        (Some(template), None) => {
+            validate_prompt_template(&template)?;
+            Ok((template, None))
+        }
+        (None, Some(name)) => {
+            let template = get_prompt_template_from_memory(&name)?;
+            Ok((template.template, Some(name)))
+        }
+        (None, None) => Ok((default_template.to_string(), None)),
+    }
+}
+
+/// Saves a named prompt template in the library, so it can be used in LLM fairness evaluations.
+/// If a template with the same name exists, it's replaced.
+///
+/// # Parameters
+/// - `name: String`: template name.
+/// - `description: String`: template description.
+/// - `template: String`: template text, with the `<EXAMPLE_0>` to `<EXAMPLE_3>` (or `<EXAMPLES>`) and `*?*` placeholders.
+#[ic_cdk::update]
+pub fn add_llm_fairness_prompt_template(
+    name: String,
+    description: String,
+    template: String,
+) -> Result<(), GenericError> {
+    only_admin();
+    check_cycles_before_action();
+
+    if name.trim().is_empty() {
+        return Err(GenericError::new(
+            GenericError::EMPTY_INPUT,
+            "Template name cannot be empty.",
+        ));
+    }
+    validate_prompt_template(&template)?;
+
+    LLM_FAIRNESS_PROMPT_TEMPLATES.with(|templates| {
+        templates.borrow_mut().insert(
+            name.clone(),
+            LLMFairnessPromptTemplate {
+                name,
+                description,
+                template,
+                timestamp: ic_cdk::api::time(),
+            },
+        );
+    });
+
+    Ok(())
+}
+
+/// Removes a prompt template from the library. Evaluations already saved keep the template text.
+#[ic_cdk::update]
+pub fn remove_llm_fairness_prompt_template(name: String) -> Result<(), GenericError> {
+    only_admin();
+    check_cycles_before_action();
+
+    LLM_FAIRNESS_PROMPT_TEMPLATES
+        .with(|templates| templates.borrow_mut().remove(&name))
+        .map(|_| ())
+        .ok_or(GenericError::new(
+            GenericError::NOT_FOUND,
+            format!("Prompt template {} not found", name),
+        ))
+}
+
+/// Returns all the prompt templates in the library
+#[ic_cdk::query]
+pub fn get_llm_fairness_prompt_templates() -> Vec<LLMFairnessPromptTemplate> {
+    check_cycles_before_action();
+
+    LLM_FAIRNESS_PROMPT_TEMPLATES.with(|templates| templates.borrow().values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE: &str = "<EXAMPLE_0> <EXAMPLE_1> <EXAMPLE_2> <EXAMPLE_3> *?*";
+
+    #[test]
+    fn test_default_template() {
+        assert_eq!(
+            resolve_prompt_template(TEMPLATE, None, None),
+            Ok((TEMPLATE.to_string(), None))
+        );
+    }
+
+    #[test]
+    fn test_custom_template_is_validated() {
+        let custom = format!("Be fair. {}", TEMPLATE);
+        assert_eq!(
+            resolve_prompt_template(TEMPLATE, Some(custom.clone()), None),
+            Ok((custom, None))
+        );
+
+        let err = resolve_prompt_template(TEMPLATE, Some("<EXAMPLE_0> *?*".to_string()), None)
+            .unwrap_err();
+        assert_eq!(err.code, GenericError::INVALID_ARGUMENT);
+
+        // The examples block can replace the numbered placeholders
+        assert!(resolve_prompt_template(TEMPLATE, Some("<EXAMPLES> *?*".to_string()), None).is_ok());
+    }
+
+    #[test]
+    fn test_custom_template_and_name_are_exclusive() {
+        let result = resolve_prompt_template(
+            TEMPLATE,
+            Some(TEMPLATE.to_string()),
+            Some("neutral".to_string()),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_unknown_template_name() {
+        let err = resolve_prompt_template(TEMPLATE, None, Some("unknown".to_string())).unwrap_err();
+        assert_eq!(err.code, GenericError::NOT_FOUND);
+    }
+}
@@ -1,9 +1,9 @@
 use crate::errors::GenericError;
-use crate::hugging_face::call_hugging_face;
+use crate::hugging_face::{call_hugging_face, resolve_generation_parameters};
 use crate::inference_providers::lib::HuggingFaceRequestParameters;
 use crate::job_management::{internal_job_complete, internal_job_fail, internal_job_in_progress, create_job_with_job_type, bootstrap_job_queue, job_should_be_stopped, internal_job_stop};
 use crate::types::{
-    get_llm_model_data, LanguageEvaluationDataPoint, LanguageEvaluationMetrics,
+    get_llm_model_data, GenerationParameters, LanguageEvaluationDataPoint, LanguageEvaluationMetrics,
     LanguageEvaluationResult, ModelType, Job, JobType, HuggingFaceConfig,
 };
 use crate::utils::{is_owner, seeded_vector_shuffle};
@@ -29,6 +29,16 @@ output your final answer in JSON format with the following structure: {\"choice\
 \"The correct option\"}. ONLY output this format exactly. Do
 not include any additional text or explanations outside the JSON structure.";
 
+/// Generation parameters of language evaluations, when neither the provider nor the caller set them
+fn language_evaluation_default_generation_parameters() -> GenerationParameters {
+    GenerationParameters {
+        max_new_tokens: None,
+        temperature: Some(0.3),
+        stop: None,
+        do_sample: Some(false),
+    }
+}
+
 fn build_prompt(question: &String, options: &Vec<String>, seed: u32) -> String {
     let mut prompt = String::with_capacity(
         SYSTEM_PROMPT.len()
@@ -69,16 +79,16 @@ async fn run_evaluate_languages(
     let seed = language_evaluation.seed;
     let max_queries = language_evaluation.max_queries;
     let current_query = job.progress.completed;
-    let hf_parameters = HuggingFaceRequestParameters {
-        max_new_tokens: None,
-        stop: None,
-        temperature: Some(0.3),
-        decoder_input_details: Some(false),
-        details: Some(false),
-        return_full_text: Some(false),
-        seed: Some(seed),
-        do_sample: Some(false),
+    // Evaluations created before the parameters were stored use the defaults
+    let generation_parameters = match &language_evaluation.generation_parameters {
+        Some(generation_parameters) => generation_parameters.clone(),
+        None => resolve_generation_parameters(
+            &hf_data.inference_provider,
+            language_evaluation_default_generation_parameters(),
+            None,
+        )?,
     };
+    let hf_parameters = HuggingFaceRequestParameters::from_generation_parameters(&generation_parameters, seed);
 
     let mut queries: usize = 0; // query counter
     let mut rdr = csv::ReaderBuilder::new().from_reader(KALEIDOSKOPE_CSV.as_bytes());
@@ -167,6 +177,7 @@ async fn run_evaluate_languages(
                     valid: false,
                     error: true,
                     correct_answer: text_answer.clone(),
+                    language: Some(language.clone()),
                 });
 
                 return Ok(());
@@ -187,10 +198,12 @@ async fn run_evaluate_languages(
 
                 language_evaluation.data_points.push(LanguageEvaluationDataPoint {
                     prompt: prompt.clone(),
-                    response: None,
+                    // Keeping the whole response, so refusals can be detected
+                    response: Some(trimmed_response.clone()),
                     valid: false,
                     error: false,
                     correct_answer: text_answer.clone(),
+                    language: Some(language.clone()),
                 });
 
                 return Ok(());
@@ -205,6 +218,7 @@ async fn run_evaluate_languages(
             valid: false,
             error: false,
             correct_answer: text_answer.clone(),
+            language: Some(language.clone()),
         });
 
         if llm_answer.to_lowercase() == text_answer.trim().to_lowercase() {
@@ -237,12 +251,14 @@ async fn run_evaluate_languages(
 
 /// Evaluates languages for a LLM. It returns the LanguageEvaluationResult,
 /// and it also saves the result into the model data.
+/// `generation_parameters` overrides the default generation parameters.
 #[update]
 pub async fn llm_evaluate_languages(
     model_id: u128,
     languages: Vec<String>,
     max_queries: usize,
     seed: u32,
+    generation_parameters: Option<GenerationParameters>,
 ) -> Result<u128, GenericError> {
     only_admin();
     check_cycles_before_action();
@@ -276,7 +292,13 @@ pub async fn llm_evaluate_languages(
     let model = model.unwrap();
     is_owner(&model, caller);
 
-    if let ModelType::LLM(_) = model.model_type {
+    if let ModelType::LLM(llm_model_data) = model.model_type {
+        let generation_parameters = resolve_generation_parameters(
+            &llm_model_data.inference_provider,
+            language_evaluation_default_generation_parameters(),
+            generation_parameters,
+        )?;
+
         let created_job = MODELS.with(|models| {
             let mut models = models.borrow_mut();
             let mut model = models.get(&model_id).expect("Model not found");
@@ -320,7 +342,8 @@ pub async fn llm_evaluate_languages(
                     seed,
                     finished: false,
                     canceled: false,
-                    job_id: Some(job_id),        
+                    job_id: Some(job_id),
+                    generation_parameters: Some(generation_parameters.clone()),
                 });
 
                 let current_id = *next_data_point_id.get();
@@ -1,4 +1,4 @@
-use crate::types::{{CachedThresholds, PrivilegedIndex, ModelType, get_classifier_model_data}};
+use crate::types::{{AverageMetrics, CachedThresholds, Metrics, PrivilegedIndex, ModelType, get_classifier_model_data}};
 use crate::{
     check_cycles_before_action, is_owner, DataPoint, MODELS
 };
@@ -314,6 +314,17 @@ pub(crate) fn calculate_all_metrics(
     (spd, di, aod, eod, acc, prec, rec)
 }
 
+/// Returns if a sensible value belongs to the privileged group,
+/// given a threshold and if higher values are privileged.
+pub(crate) fn is_privileged_value(value: f64, threshold: (f64, bool)) -> bool {
+    let (threshold, greater_than) = threshold;
+    if greater_than {
+        value > threshold
+    } else {
+        value < threshold
+    }
+}
+
 /// Calculates group counts for privileged and unprivileged groups based on specified thresholds.
 ///
 /// This function separates the data points into privileged and unprivileged groups according to 
@@ -970,6 +981,65 @@ pub(crate) fn all_metrics(data_points: &Vec<DataPoint>, privilieged_threshold: O
     (spd, di, aod, eod, acc, prec, rec)
 }
 
+/// Calculates all the classifier metrics that can be calculated for the data points.
+/// Unlike `all_metrics`, it doesn't trap when a group is missing or precision/recall are undefined,
+/// and those metrics are set to `None` instead.
+pub(crate) fn metrics_from_data_points(data_points: &Vec<DataPoint>, privilieged_threshold: Option<HashMap<String, (f64, bool)>>, timestamp: u64) -> Metrics {
+    let mut metrics = Metrics {
+        statistical_parity_difference: None,
+        disparate_impact: None,
+        average_odds_difference: None,
+        equal_opportunity_difference: None,
+        average_metrics: AverageMetrics {
+            statistical_parity_difference: None,
+            disparate_impact: None,
+            average_odds_difference: None,
+            equal_opportunity_difference: None,
+        },
+        accuracy: None,
+        precision: None,
+        recall: None,
+        timestamp,
+    };
+
+    if data_points.is_empty() {
+        return metrics;
+    }
+
+    let (privileged_count, unprivileged_count, _, _) =
+        calculate_group_counts(data_points, privilieged_threshold.clone());
+
+    if !privileged_count.is_empty() && !unprivileged_count.is_empty() {
+        let spd = statistical_parity_difference(data_points, privilieged_threshold.clone());
+        let di = disparate_impact(data_points, privilieged_threshold.clone());
+        let aod = average_odds_difference(data_points, privilieged_threshold.clone());
+        let eod = equal_opportunity_difference(data_points, privilieged_threshold);
+
+        metrics.statistical_parity_difference = Some(spd.0);
+        metrics.disparate_impact = Some(di.0);
+        metrics.average_odds_difference = Some(aod.0);
+        metrics.equal_opportunity_difference = Some(eod.0);
+        metrics.average_metrics = AverageMetrics {
+            statistical_parity_difference: Some(spd.1),
+            disparate_impact: Some(di.1),
+            average_odds_difference: Some(aod.1),
+            equal_opportunity_difference: Some(eod.1),
+        };
+    }
+
+    metrics.accuracy = Some(accuracy(data_points));
+
+    let (tp, _, fp, fn_) = calculate_overall_confusion_matrix(data_points);
+    if can_calculate_precision(tp, fp) {
+        metrics.precision = Some(precision(data_points));
+    }
+    if can_calculate_recall(tp, fn_) {
+        metrics.recall = Some(recall(data_points));
+    }
+
+    metrics
+}
+
 #[cfg(test)]
 mod metrics_calculation_tests {
     use std::collections::HashMap;
@@ -1272,5 +1342,24 @@ mod metrics_calculation_tests {
             let _rec = recall(&data);
         }
     }
-}
 
+    #[cfg(test)]
+    mod test_metrics_from_data_points {
+        use super::*;
+
+        #[test]
+        fn test_missing_group_does_not_trap() {
+            let data = vec![
+                DataPoint { data_point_id: 1, target: false, predicted: false, privileged_map: gender_pmap(), features: vec![0.0], timestamp: 0 },
+                DataPoint { data_point_id: 2, target: false, predicted: true, privileged_map: gender_pmap(), features: vec![0.0], timestamp: 0 },
+            ];
+            let metrics = metrics_from_data_points(&data, None, 10);
+
+            assert_eq!(metrics.statistical_parity_difference, None);
+            assert_eq!(metrics.recall, None);
+            assert_eq!(metrics.precision, Some(0.0));
+            assert_eq!(metrics.accuracy, Some(0.5));
+            assert_eq!(metrics.timestamp, 10);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use crate::types::get_classifier_model_data;
-use crate::types::{ClassifierModelData, LLMModelData, ModelDetailsHistory, ModelType};
+use crate::types::{
+    ClassifierModelData, EmbeddingModelData, LLMModelData, ModelDetailsHistory, ModelType,
+};
 use crate::{
     check_cycles_before_action, is_owner, only_admin, AverageMetrics, DataPoint, Metrics, Model,
     ModelDetails, MODELS, NEXT_MODEL_ID,
@@ -54,6 +56,12 @@ pub fn add_classifier_model(model_name: String, model_details: ModelDetails) ->
                             timestamp: 0,
                         },
                         metrics_history: Vec::new(),
+                        predictor: None,
+                        counter_factual: None,
+                        remote_evaluations: None,
+                        individual_fairness: None,
+                        feature_attribution: None,
+                        dataset_evaluations: None,
                     }),
                     cached_thresholds: None,
                     cached_selections: None,
@@ -110,6 +118,13 @@ pub fn add_llm_model(
                         average_fairness_metrics: None,
                         language_evaluations: Vec::new(),
                         inference_provider,
+                        multi_seed_evaluations: None,
+                        persona_bias_evaluations: None,
+                        bbq_evaluations: None,
+                        crows_pairs_metrics_history: None,
+                        winobias_evaluations: None,
+                        open_generation_evaluations: None,
+                        prompt_robustness_evaluations: None,
                     }),
                     cached_thresholds: None,
                     cached_selections: None,
@@ -125,6 +140,52 @@ pub fn add_llm_model(
     id
 }
 
+#[ic_cdk::update]
+pub fn add_embedding_model(model_name: String, model_details: ModelDetails) -> u128 {
+    only_admin();
+    check_cycles_before_action();
+
+    if model_name.trim().is_empty() {
+        ic_cdk::api::trap("Error: Model name cannot be empty or null.");
+    }
+
+    let caller: Principal = ic_cdk::api::caller();
+
+    let id = MODELS.with(|models| {
+        NEXT_MODEL_ID.with(|id| {
+            let current_id = *id.borrow().get();
+
+            models.borrow_mut().insert(
+                current_id,
+                Model {
+                    model_id: current_id,
+                    model_name: model_name.clone(),
+                    owners: vec![caller],
+                    details: model_details.clone(),
+                    details_history: vec![ModelDetailsHistory {
+                        name: model_name,
+                        details: model_details,
+                        version: 0,
+                        timestamp: ic_cdk::api::time(),
+                    }],
+                    model_type: ModelType::Embedding(EmbeddingModelData {
+                        embeddings: Vec::new(),
+                        association_tests_history: Vec::new(),
+                    }),
+                    cached_thresholds: None,
+                    cached_selections: None,
+                    version: 0,
+                },
+            );
+
+            id.borrow_mut().set(current_id + 1).unwrap();
+
+            current_id
+        })
+    });
+    id
+}
+
 #[ic_cdk::update]
 pub fn delete_model(model_id: u128) {
     check_cycles_before_action();
@@ -153,7 +214,10 @@ pub fn get_all_models(limit: usize, _offset: usize, model_type: Option<String>)
                     Some(ref mt) if mt == "classifier" => {
                         matches!(model.model_type, ModelType::Classifier(_))
                     }
-                    // if model type is not "llm" or "classifier", it matches everything
+                    Some(ref mt) if mt == "embedding" => {
+                        matches!(model.model_type, ModelType::Embedding(_))
+                    }
+                    // if model type is not "llm", "classifier" or "embedding", it matches everything
                     _ => true,
                 }
             })
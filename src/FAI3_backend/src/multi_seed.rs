@@ -0,0 +1,401 @@
+use crate::admin_management::only_admin;
+use crate::job_management::{
+    bootstrap_job_queue, create_job_with_job_type, get_job, internal_job_complete, internal_job_fail,
+    internal_job_in_progress, JOB_STATUS_COMPLETED,
+};
+use crate::llm_fairness::{prepare_llm_fairness_evaluation, queue_llm_fairness_evaluation};
+use crate::types::{
+    get_llm_model_data, AverageMetricsStatistics, Job, JobType, LLMFairnessEvaluationOptions,
+    MetricStatistics, MetricsStatistics, ModelEvaluationResult, ModelType,
+    MultiSeedEvaluationResult, PrivilegedIndex, PrivilegedIndexStatistics,
+};
+use crate::utils::is_owner;
+use crate::{check_cycles_before_action, get_model_from_memory, MODELS, NEXT_MULTI_SEED_EVALUATION_ID};
+use ic_cdk_macros::*;
+use std::collections::BTreeMap;
+
+/// Calculates the statistics of a metric. Values that are not finite (e.g. a division by zero) are skipped.
+/// Returns None if there are no values left.
+pub fn metric_statistics(values: impl IntoIterator<Item = f32>) -> Option<MetricStatistics> {
+    let values: Vec<f32> = values.into_iter().filter(|value| value.is_finite()).collect();
+    if values.is_empty() {
+        return None;
+    }
+
+    let n = values.len() as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let std_dev = if values.len() > 1 {
+        (values.iter().map(|value| (value - mean).powi(2)).sum::<f32>() / (n - 1.0)).sqrt()
+    } else {
+        0.0
+    };
+
+    Some(MetricStatistics {
+        mean,
+        std_dev,
+        min: values.iter().cloned().fold(f32::INFINITY, f32::min),
+        max: values.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+        n: values.len() as u32,
+    })
+}
+
+/// Calculates the statistics of a group metric for every variable
+fn privileged_index_statistics<'a>(
+    runs: impl Iterator<Item = &'a Option<Vec<PrivilegedIndex>>>,
+) -> Option<Vec<PrivilegedIndexStatistics>> {
+    let mut values: BTreeMap<String, Vec<f32>> = BTreeMap::new();
+    for indexes in runs.flatten() {
+        for index in indexes {
+            values
+                .entry(index.variable_name.clone())
+                .or_default()
+                .push(index.value);
+        }
+    }
+
+    let statistics: Vec<PrivilegedIndexStatistics> = values
+        .into_iter()
+        .filter_map(|(variable_name, values)| {
+            metric_statistics(values).map(|statistics| PrivilegedIndexStatistics {
+                variable_name,
+                statistics,
+            })
+        })
+        .collect();
+
+    if statistics.is_empty() {
+        None
+    } else {
+        Some(statistics)
+    }
+}
+
+/// Calculates the statistics of the metrics and the counter factual change rate of several evaluations
+pub fn aggregate_evaluations(
+    evaluations: &[&ModelEvaluationResult],
+) -> (MetricsStatistics, Option<MetricStatistics>) {
+    let metrics = evaluations.iter().map(|evaluation| &evaluation.metrics);
+    let average = |f: fn(&ModelEvaluationResult) -> Option<f32>| {
+        metric_statistics(evaluations.iter().filter_map(|evaluation| f(evaluation)))
+    };
+
+    let statistics = MetricsStatistics {
+        statistical_parity_difference: privileged_index_statistics(
+            metrics.clone().map(|m| &m.statistical_parity_difference),
+        ),
+        disparate_impact: privileged_index_statistics(metrics.clone().map(|m| &m.disparate_impact)),
+        average_odds_difference: privileged_index_statistics(
+            metrics.clone().map(|m| &m.average_odds_difference),
+        ),
+        equal_opportunity_difference: privileged_index_statistics(
+            metrics.clone().map(|m| &m.equal_opportunity_difference),
+        ),
+        average_metrics: AverageMetricsStatistics {
+            statistical_parity_difference: average(|e| {
+                e.metrics.average_metrics.statistical_parity_difference
+            }),
+            disparate_impact: average(|e| e.metrics.average_metrics.disparate_impact),
+            average_odds_difference: average(|e| e.metrics.average_metrics.average_odds_difference),
+            equal_opportunity_difference: average(|e| {
+                e.metrics.average_metrics.equal_opportunity_difference
+            }),
+        },
+        accuracy: average(|e| e.metrics.accuracy),
+        precision: average(|e| e.metrics.precision),
+        recall: average(|e| e.metrics.recall),
+    };
+
+    let counter_factual_change_rate =
+        average(|e| e.counter_factual.as_ref().map(|cf| cf.change_rate_overall));
+
+    (statistics, counter_factual_change_rate)
+}
+
+/// Runs the same LLM fairness evaluation with several seeds, as a group of linked jobs.
+/// Once all the evaluations are done, another job calculates the mean, standard deviation and range of every metric.
+///
+/// # Parameters
+/// - `llm_model_id: u128`: Unique identifier for the LLM model.
+/// - `dataset: String`: dataset to be tested.
+/// - `max_queries: usize`: Max queries to execute for every seed. If it's 0, it will execute all the queries.
+/// - `seeds: Vec<u32>`: seeds to run, at least two different ones.
+/// - `max_errors: u32`: Max call errors before a seed job is canceled. If it's 0, there is no limit.
+/// - `options: Option<LLMFairnessEvaluationOptions>`: optional settings, shared by all the seeds.
+///
+/// # Returns
+/// - `Result<u128, String>`: the multi seed evaluation id.
+#[update]
+pub async fn calculate_llm_metrics_multi_seed(
+    llm_model_id: u128,
+    dataset: String,
+    max_queries: usize,
+    seeds: Vec<u32>,
+    max_errors: u32,
+    options: Option<LLMFairnessEvaluationOptions>,
+) -> Result<u128, String> {
+    only_admin();
+    check_cycles_before_action();
+
+    let model = get_model_from_memory(llm_model_id)?;
+    is_owner(&model, ic_cdk::api::caller());
+
+    if !matches!(model.model_type, ModelType::LLM(_)) {
+        return Err("Model should be a LLM".to_string());
+    }
+
+    if seeds.len() < 2 {
+        return Err("At least two seeds are required.".to_string());
+    }
+    if seeds.iter().enumerate().any(|(i, seed)| seeds[..i].contains(seed)) {
+        return Err("Seeds should be different.".to_string());
+    }
+
+    // Every seed is checked before queueing any job
+    let prepared = seeds
+        .iter()
+        .map(|seed| {
+            prepare_llm_fairness_evaluation(&model, dataset.clone(), max_queries, *seed, max_errors, options.clone())
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let (job_ids, model_evaluation_ids): (Vec<u128>, Vec<u128>) = prepared
+        .into_iter()
+        .map(|prepared| queue_llm_fairness_evaluation(llm_model_id, prepared))
+        .unzip();
+
+    let multi_seed_evaluation_id = NEXT_MULTI_SEED_EVALUATION_ID.with(|id| {
+        let mut next_id = id.borrow_mut();
+        let current_id = *next_id.get();
+        next_id.set(current_id + 1).unwrap();
+        current_id
+    });
+
+    let job_id = create_job_with_job_type(
+        llm_model_id,
+        JobType::MultiSeedAggregation {
+            multi_seed_evaluation_id,
+            job_dependencies: job_ids.clone(),
+        },
+        1,
+    );
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&llm_model_id).expect("Model not found");
+        let mut model_data = get_llm_model_data(&model);
+
+        model_data
+            .multi_seed_evaluations
+            .get_or_insert_with(Vec::new)
+            .push(MultiSeedEvaluationResult {
+                multi_seed_evaluation_id,
+                dataset,
+                seeds,
+                model_evaluation_ids,
+                job_ids,
+                job_id,
+                timestamp: ic_cdk::api::time(),
+                finished: false,
+                metrics: None,
+                counter_factual_change_rate: None,
+            });
+
+        model.model_type = ModelType::LLM(model_data);
+        models.insert(llm_model_id, model);
+    });
+
+    bootstrap_job_queue();
+
+    Ok(multi_seed_evaluation_id)
+}
+
+/// Calculates the statistics of a multi seed evaluation, once all its seed jobs are completed
+pub fn process_multi_seed_aggregation_from_job(
+    job: &Job,
+    multi_seed_evaluation_id: u128,
+    job_dependencies: Vec<u128>,
+) -> Result<bool, String> {
+    let llm_model_id = job.model_id;
+
+    if let Some(dependency) = job_dependencies
+        .iter()
+        .filter_map(|job_id| get_job(*job_id))
+        .find(|dependency| dependency.status != JOB_STATUS_COMPLETED)
+    {
+        let error = format!(
+            "Job with id = {} has status = {}, expected status = {}",
+            dependency.id, &dependency.status, JOB_STATUS_COMPLETED
+        );
+        ic_cdk::eprintln!("{}", &error);
+        internal_job_fail(job.id, llm_model_id, Some(error));
+        return Ok(true);
+    }
+
+    let model = get_model_from_memory(llm_model_id).map_err(|e| e.to_string())?;
+    let model_data = get_llm_model_data(&model);
+
+    let multi_seed_evaluation = model_data
+        .multi_seed_evaluations
+        .iter()
+        .flatten()
+        .find(|evaluation| evaluation.multi_seed_evaluation_id == multi_seed_evaluation_id);
+    let multi_seed_evaluation = match multi_seed_evaluation {
+        Some(evaluation) => evaluation,
+        None => {
+            let error = format!(
+                "Multi seed evaluation with id = {} does not exist for model with id = {}",
+                multi_seed_evaluation_id, llm_model_id
+            );
+            ic_cdk::eprintln!("{}", &error);
+            internal_job_fail(job.id, llm_model_id, Some(error));
+            return Ok(true);
+        }
+    };
+
+    let evaluations: Vec<&ModelEvaluationResult> = model_data
+        .evaluations
+        .iter()
+        .filter(|evaluation| {
+            multi_seed_evaluation
+                .model_evaluation_ids
+                .contains(&evaluation.model_evaluation_id)
+        })
+        .collect();
+
+    if evaluations.len() != multi_seed_evaluation.model_evaluation_ids.len() {
+        let error = "Some evaluations of the multi seed evaluation do not exist".to_string();
+        ic_cdk::eprintln!("{}", &error);
+        internal_job_fail(job.id, llm_model_id, Some(error));
+        return Ok(true);
+    }
+
+    let (metrics, counter_factual_change_rate) = aggregate_evaluations(&evaluations);
+
+    internal_job_in_progress(job.id, llm_model_id, 1, 0, 0);
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&llm_model_id).expect("Model not found");
+        let mut model_data = get_llm_model_data(&model);
+
+        if let Some(evaluation) = model_data
+            .multi_seed_evaluations
+            .iter_mut()
+            .flatten()
+            .find(|evaluation| evaluation.multi_seed_evaluation_id == multi_seed_evaluation_id)
+        {
+            evaluation.metrics = Some(metrics);
+            evaluation.counter_factual_change_rate = counter_factual_change_rate;
+            evaluation.finished = true;
+        }
+
+        model.model_type = ModelType::LLM(model_data);
+        models.insert(llm_model_id, model);
+    });
+
+    internal_job_complete(job.id, llm_model_id);
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{AverageMetrics, CounterFactualModelEvaluationResult, Metrics};
+
+    fn evaluation(spd: f32, accuracy: Option<f32>, change_rate: f32) -> ModelEvaluationResult {
+        ModelEvaluationResult {
+            model_evaluation_id: 0,
+            dataset: "pisa".to_string(),
+            timestamp: 0,
+            metrics: Metrics {
+                statistical_parity_difference: Some(vec![PrivilegedIndex {
+                    variable_name: "gender".to_string(),
+                    value: spd,
+                }]),
+                disparate_impact: None,
+                average_odds_difference: None,
+                equal_opportunity_difference: None,
+                average_metrics: AverageMetrics {
+                    statistical_parity_difference: Some(spd),
+                    disparate_impact: None,
+                    average_odds_difference: None,
+                    equal_opportunity_difference: None,
+                },
+                accuracy,
+                precision: None,
+                recall: None,
+                timestamp: 0,
+            },
+            privileged_map: Vec::new(),
+            queries: 0,
+            max_queries: 0,
+            max_errors: 0,
+            invalid_responses: 0,
+            errors: 0,
+            seed: 0,
+            data_points: None,
+            llm_data_points: None,
+            prompt_template: None,
+            counter_factual: Some(CounterFactualModelEvaluationResult {
+                change_rate_overall: change_rate,
+                change_rate_sensible_attributes: Vec::new(),
+                total_sensible_attributes: Vec::new(),
+                sensible_attribute: "gender".to_string(),
+                change_rate_value_pairs: None,
+            }),
+            finished: true,
+            canceled: false,
+            job_id: None,
+            counter_factual_source: None,
+            prompt_template_name: None,
+            few_shot: None,
+            answer_extraction: None,
+            rescored_from: None,
+            generation_parameters: None,
+            test_row_sampling: None,
+            sampled_test_rows: None,
+            additional_sensible_attributes: None,
+            sensible_attributes: None,
+            sensible_attribute_results: None,
+        }
+    }
+
+    #[test]
+    fn test_metric_statistics() {
+        let statistics = metric_statistics(vec![2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]).unwrap();
+
+        assert_eq!(statistics.mean, 5.0);
+        assert!((statistics.std_dev - 2.138).abs() < 0.001);
+        assert_eq!(statistics.min, 2.0);
+        assert_eq!(statistics.max, 9.0);
+        assert_eq!(statistics.n, 8);
+
+        assert_eq!(metric_statistics(vec![f32::NAN]), None);
+        assert_eq!(metric_statistics(vec![1.0, f32::NAN]).unwrap().std_dev, 0.0);
+    }
+
+    #[test]
+    fn test_aggregate_evaluations() {
+        let evaluations = [
+            evaluation(0.1, Some(0.5), 0.2),
+            evaluation(0.3, None, 0.4),
+            evaluation(0.2, Some(0.7), f32::NAN),
+        ];
+        let evaluations: Vec<&ModelEvaluationResult> = evaluations.iter().collect();
+
+        let (metrics, change_rate) = aggregate_evaluations(&evaluations);
+
+        let spd = metrics.statistical_parity_difference.unwrap();
+        assert_eq!(spd.len(), 1);
+        assert_eq!(spd[0].variable_name, "gender");
+        assert!((spd[0].statistics.mean - 0.2).abs() < 1e-6);
+        assert_eq!(spd[0].statistics.min, 0.1);
+        assert_eq!(spd[0].statistics.max, 0.3);
+
+        assert_eq!(metrics.accuracy.unwrap().n, 2);
+        assert_eq!(metrics.disparate_impact, None);
+        assert_eq!(metrics.average_metrics.disparate_impact, None);
+        assert!((change_rate.unwrap().mean - 0.3).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::config_management::{internal_get_config, HUGGING_FACE_API_KEY_CONFIG_KEY};
+use crate::errors::GenericError;
+use crate::job_management::{
+    bootstrap_job_queue, create_job_with_job_type, internal_job_complete, internal_job_fail,
+    internal_job_in_progress, internal_job_stop, job_should_be_stopped,
+};
+use crate::types::{get_llm_model_data, HuggingFaceConfig, Job, JobType, LLMModelData, ModelType};
+use crate::utils::is_owner;
+use crate::{get_model_from_memory, MODELS};
+
+/// LLM evaluation stored in the model that runs one query per job step,
+/// like the persona bias or BBQ tests.
+pub(crate) trait QueryJobEvaluation: Clone + Sized {
+    type DataPoint: Clone;
+
+    /// Name used in logs and errors, e.g. "BBQ evaluation"
+    const NAME: &'static str;
+
+    fn id(&self) -> u128;
+
+    /// Evaluations of this type saved in the model
+    fn evaluations(model_data: &mut LLMModelData) -> &mut Option<Vec<Self>>;
+
+    fn is_finished(&self) -> bool;
+
+    /// Marks the evaluation as finished and canceled
+    fn cancel(&mut self);
+
+    fn max_errors(&self) -> u32;
+
+    fn errors(&self) -> u32;
+
+    fn data_points(&self) -> &[Self::DataPoint];
+
+    /// Whether the answer of a data point couldn't be used. Call errors are not invalid responses
+    fn is_invalid(data_point: &Self::DataPoint) -> bool;
+
+    /// Saves the result of a query, counting it as an error if the call failed
+    fn add_data_point(&mut self, data_point: Self::DataPoint);
+
+    /// Calculates the metrics and marks the evaluation as finished, once all the queries are done
+    fn finish(&mut self) -> Result<(), String>;
+}
+
+/// Creates the job of a query evaluation and saves the evaluation in the model.
+///
+/// # Parameters
+/// - `model_id: u128`: LLM model id.
+/// - `job_type: JobType`: job type, which holds the evaluation id.
+/// - `job_queries_target: usize`: number of queries to run.
+/// - `evaluation: impl FnOnce(u128) -> E`: builds the evaluation from the job id.
+///
+/// # Returns
+/// - The job id.
+pub(crate) fn create_query_job<E: QueryJobEvaluation>(
+    model_id: u128,
+    job_type: JobType,
+    job_queries_target: usize,
+    evaluation: impl FnOnce(u128) -> E,
+) -> u128 {
+    let job_id = create_job_with_job_type(model_id, job_type, job_queries_target);
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&model_id).expect("Model not found");
+        let mut model_data = get_llm_model_data(&model);
+
+        E::evaluations(&mut model_data)
+            .get_or_insert_with(Vec::new)
+            .push(evaluation(job_id));
+
+        model.model_type = ModelType::LLM(model_data);
+        models.insert(model_id, model);
+    });
+
+    bootstrap_job_queue();
+
+    job_id
+}
+
+/// Updates a query evaluation of a model
+fn update_evaluation<E: QueryJobEvaluation>(llm_model_id: u128, evaluation_id: u128, update: impl FnOnce(&mut E)) {
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&llm_model_id).expect("Model not found");
+        let mut model_data = get_llm_model_data(&model);
+
+        if let Some(evaluation) = E::evaluations(&mut model_data)
+            .iter_mut()
+            .flatten()
+            .find(|e| e.id() == evaluation_id)
+        {
+            update(evaluation);
+        }
+
+        model.model_type = ModelType::LLM(model_data);
+        models.insert(llm_model_id, model);
+    });
+}
+
+/// Fails the job and cancels its evaluation
+pub(crate) fn cancel_query_job<E: QueryJobEvaluation>(llm_model_id: u128, evaluation_id: u128, job: &Job, error: String) {
+    ic_cdk::eprintln!("{}", &error);
+    internal_job_fail(job.id, llm_model_id, Some(error));
+    update_evaluation(llm_model_id, evaluation_id, E::cancel);
+}
+
+/// Runs the checks shared by every query job before a query: the job has not been stopped,
+/// not all the queries have been run (if so, the metrics are calculated), and the errors are under the limit.
+///
+/// # Returns
+/// - `Ok(None)` if the job has finished, and the evaluation and the model connection details if there's a query to run.
+pub(crate) fn next_query<E: QueryJobEvaluation>(
+    llm_model_id: u128,
+    evaluation_id: u128,
+    job: &Job,
+) -> Result<Option<(E, HuggingFaceConfig)>, String> {
+    let model = get_model_from_memory(llm_model_id)?;
+    let mut model_data = get_llm_model_data(&model);
+
+    let hf_data = HuggingFaceConfig {
+        hugging_face_url: model_data.hugging_face_url.clone(),
+        inference_provider: model_data.inference_provider.clone(),
+    };
+
+    let mut evaluation = match E::evaluations(&mut model_data)
+        .take()
+        .into_iter()
+        .flatten()
+        .find(|e| e.id() == evaluation_id)
+    {
+        Some(evaluation) => evaluation,
+        None => {
+            let error = format!("{} not found", E::NAME);
+            ic_cdk::eprintln!("Error: {}", error);
+            internal_job_fail(job.id, llm_model_id, Some(error));
+            return Ok(None);
+        }
+    };
+
+    if evaluation.is_finished() {
+        ic_cdk::println!("{} already finished. Exiting...", E::NAME);
+        return Ok(None);
+    }
+
+    if job_should_be_stopped(job.id) {
+        ic_cdk::eprintln!("Job has been stopped while running. Marking {} as finished and cancelled.", E::NAME);
+        internal_job_stop(job.id, job.model_id);
+        update_evaluation(llm_model_id, evaluation_id, E::cancel);
+        return Ok(None);
+    }
+
+    // Get API key for Hugging Face
+    let _api_key = internal_get_config(HUGGING_FACE_API_KEY_CONFIG_KEY.to_string())?;
+
+    if job.progress.completed >= job.progress.target {
+        evaluation.finish()?;
+        update_evaluation(llm_model_id, evaluation_id, |stored: &mut E| *stored = evaluation);
+
+        internal_job_complete(job.id, llm_model_id);
+        ic_cdk::println!("Job {} is complete.", job.id);
+        return Ok(None);
+    }
+
+    if evaluation.max_errors() != 0 && evaluation.errors() > evaluation.max_errors() {
+        let error = format!("Max errors limit of {} reached. Cancelling job.", evaluation.max_errors());
+        cancel_query_job::<E>(llm_model_id, evaluation_id, job, error);
+        return Ok(None);
+    }
+
+    Ok(Some((evaluation, hf_data)))
+}
+
+/// Saves the result of a query and updates the job progress
+pub(crate) fn save_query_result<E: QueryJobEvaluation>(
+    llm_model_id: u128,
+    evaluation: &mut E,
+    job: &Job,
+    data_point: E::DataPoint,
+) {
+    evaluation.add_data_point(data_point);
+    let errors = evaluation.errors() as usize;
+    let invalid_responses = evaluation
+        .data_points()
+        .iter()
+        .filter(|data_point| E::is_invalid(data_point))
+        .count();
+
+    let saved = evaluation.clone();
+    update_evaluation(llm_model_id, evaluation.id(), |stored: &mut E| *stored = saved);
+
+    internal_job_in_progress(
+        job.id,
+        llm_model_id,
+        job.progress.completed + 1,
+        invalid_responses,
+        errors,
+    );
+}
+
+/// Returns a page of the data points
+fn paginate<T: Clone>(data_points: &[T], limit: u32, offset: usize) -> (Vec<T>, usize) {
+    let total = data_points.len();
+    let start = offset.min(total);
+    let end = offset.saturating_add(limit as usize).min(total);
+
+    (data_points[start..end].to_vec(), total)
+}
+
+/// Returns the data points of a query evaluation, paginated
+pub(crate) fn get_query_job_data_points<E: QueryJobEvaluation>(
+    llm_model_id: u128,
+    evaluation_id: u128,
+    limit: u32,
+    offset: usize,
+) -> Result<(Vec<E::DataPoint>, usize), GenericError> {
+    let caller = ic_cdk::api::caller();
+    let model = get_model_from_memory(llm_model_id)?;
+    is_owner(&model, caller);
+
+    let mut model_data = match model.model_type {
+        ModelType::LLM(model_data) => model_data,
+        _ => {
+            return Err(GenericError::new(
+                GenericError::INVALID_MODEL_TYPE,
+                "Model should be an LLM.",
+            ))
+        }
+    };
+
+    let evaluation = E::evaluations(&mut model_data)
+        .iter()
+        .flatten()
+        .find(|e| e.id() == evaluation_id)
+        .ok_or(GenericError::new(
+            GenericError::NOT_FOUND,
+            format!("{} not found", E::NAME),
+        ))?;
+
+    Ok(paginate(evaluation.data_points(), limit, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paginate() {
+        let data_points = vec![1, 2, 3, 4, 5];
+
+        assert_eq!(paginate(&data_points, 2, 0), (vec![1, 2], 5));
+        assert_eq!(paginate(&data_points, 2, 4), (vec![5], 5));
+        assert_eq!(paginate(&data_points, 2, 10), (vec![], 5));
+        assert_eq!(paginate(&data_points, u32::MAX, usize::MAX), (vec![], 5));
+    }
+}
@@ -0,0 +1,656 @@
+use crate::classifier_counter_factual::{
+    aggregate_counter_factual_predictions, CounterFactualFlips,
+};
+use crate::config_management::internal_get_config;
+use crate::errors::GenericError;
+use crate::job_management::{
+    bootstrap_job_queue, create_job_with_job_type, internal_job_complete, internal_job_fail,
+    internal_job_in_progress, internal_job_stop, job_should_be_stopped,
+};
+use crate::metrics_calculation::metrics_from_data_points;
+use crate::types::{
+    get_classifier_model_data, ClassifierCounterFactualResult, ClassifierModelData,
+    ClassifierPredictor, HttpClassifierEndpoint, Job, JobType, Model, ModelType,
+    RemoteClassifierCallError, RemoteClassifierEvaluation, RemoteClassifierRowSource, SharedDatasetEvaluation,
+};
+use crate::shared_datasets::get_shared_dataset_from_memory;
+use crate::{
+    check_cycles_before_action, get_model_from_memory, is_owner, only_admin, DataPoint, MODELS,
+    NEXT_DATA_POINT_ID, NEXT_REMOTE_CLASSIFIER_EVALUATION_ID,
+};
+
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse,
+    TransformArgs, TransformContext, TransformFunc,
+};
+use num_traits::cast::ToPrimitive;
+use serde_json::{Map, Value};
+
+/// Builds the JSON body sent to a classifier endpoint for a row of features.
+pub fn build_classifier_payload(
+    endpoint: &HttpClassifierEndpoint,
+    features: &[f64],
+) -> Result<Vec<u8>, String> {
+    let features_value = match &endpoint.feature_names {
+        Some(names) => {
+            if names.len() != features.len() {
+                return Err(format!(
+                    "Expected {} feature names but the row has {} features",
+                    names.len(),
+                    features.len()
+                ));
+            }
+            let mut object = Map::new();
+            for (name, value) in names.iter().zip(features.iter()) {
+                object.insert(name.clone(), serde_json::json!(value));
+            }
+            Value::Object(object)
+        }
+        None => serde_json::json!(features),
+    };
+
+    let mut body = Map::new();
+    body.insert(endpoint.features_field.clone(), features_value);
+
+    serde_json::to_vec(&Value::Object(body)).map_err(|e| e.to_string())
+}
+
+/// Converts the value returned by the endpoint into a prediction.
+/// Booleans are used as is, numbers are compared with the threshold,
+/// and strings are compared with the positive label (or parsed as a boolean or a number if no label is set).
+pub fn prediction_from_value(endpoint: &HttpClassifierEndpoint, value: &Value) -> Result<bool, String> {
+    let threshold = endpoint.threshold.unwrap_or(0.5);
+
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::Number(n) => n
+            .as_f64()
+            .map(|n| n >= threshold)
+            .ok_or(format!("Invalid number in prediction: {}", n)),
+        Value::String(s) => {
+            if let Some(label) = &endpoint.positive_label {
+                return Ok(s.trim() == label.as_str());
+            }
+            match s.trim().to_lowercase().as_str() {
+                "true" => Ok(true),
+                "false" => Ok(false),
+                other => other
+                    .parse::<f64>()
+                    .map(|n| n >= threshold)
+                    .map_err(|_| format!("Cannot convert prediction '{}' to a boolean", s)),
+            }
+        }
+        _ => Err(format!("Unsupported prediction value: {}", value)),
+    }
+}
+
+/// Returns the value pointed by `prediction_pointer` in the response body
+pub fn extract_prediction_value(body: &[u8], prediction_pointer: &str) -> Result<Value, String> {
+    let json: Value = serde_json::from_slice(body)
+        .map_err(|e| format!("Endpoint response is not valid JSON: {}", e))?;
+
+    json.pointer(prediction_pointer)
+        .cloned()
+        .ok_or(format!("Prediction not found in '{}'", prediction_pointer))
+}
+
+// Keeps only the prediction value, so responses are the same on every replica
+// even if the endpoint returns ids, timestamps or other variable data.
+// The JSON pointer to the prediction is passed as context.
+#[ic_cdk::query]
+fn transform_classifier_response(raw: TransformArgs) -> HttpResponse {
+    let status = raw.response.status.clone();
+    if status != 200_u16 {
+        return HttpResponse {
+            status,
+            body: raw.response.body,
+            ..Default::default()
+        };
+    }
+
+    let pointer = String::from_utf8(raw.context).unwrap_or_default();
+    let body = match extract_prediction_value(&raw.response.body, &pointer) {
+        Ok(value) => serde_json::to_vec(&value).unwrap_or_default(),
+        Err(err) => err.into_bytes(),
+    };
+
+    HttpResponse {
+        status,
+        body,
+        ..Default::default()
+    }
+}
+
+/// Calls a classifier endpoint and returns the prediction for the features.
+pub async fn call_classifier_endpoint(
+    endpoint: &HttpClassifierEndpoint,
+    features: &[f64],
+) -> Result<bool, String> {
+    let payload = build_classifier_payload(endpoint, features)?;
+
+    let mut headers = vec![HttpHeader {
+        name: "Content-Type".to_string(),
+        value: "application/json".to_string(),
+    }];
+
+    if let Some(config_key) = &endpoint.api_key_config_key {
+        let api_key = internal_get_config(config_key.clone()).map_err(|e| e.to_string())?;
+        headers.push(HttpHeader {
+            name: "Authorization".to_string(),
+            value: format!("Bearer {}", api_key),
+        });
+    }
+
+    let transform = Some(TransformContext {
+        context: endpoint.prediction_pointer.clone().into_bytes(),
+        function: TransformFunc(candid::Func {
+            principal: ic_cdk::api::id(),
+            method: "transform_classifier_response".to_string(),
+        }),
+    });
+
+    let request_arg = CanisterHttpRequestArgument {
+        url: endpoint.url.clone(),
+        method: HttpMethod::POST,
+        headers,
+        body: Some(payload),
+        max_response_bytes: Some(100_000),
+        transform,
+    };
+
+    let (response,): (HttpResponse,) = http_request(request_arg, 30000000000)
+        .await
+        .map_err(|(code, msg)| format!("HTTP request failed. Code: {:?}, Msg: {}", code, msg))?;
+
+    let status_u64: u64 = response.status.0.to_u64().unwrap_or(0);
+    if status_u64 != 200 {
+        return Err(format!(
+            "Classifier endpoint returned status {}: {}",
+            status_u64,
+            String::from_utf8_lossy(&response.body),
+        ));
+    }
+
+    // The transform function already extracted the prediction
+    let value: Value = serde_json::from_slice(&response.body).map_err(|_| {
+        format!(
+            "Invalid prediction: {}",
+            String::from_utf8_lossy(&response.body)
+        )
+    })?;
+
+    prediction_from_value(endpoint, &value)
+}
+
+/// Returns the feature rows that are sent to the endpoint
+fn remote_evaluation_rows(
+    model_data: &ClassifierModelData,
+    source: &RemoteClassifierRowSource,
+) -> Result<Vec<DataPoint>, GenericError> {
+    match source {
+        RemoteClassifierRowSource::StoredDataPoints { timestamp } => Ok(model_data
+            .data_points
+            .iter()
+            .filter(|point| point.timestamp == *timestamp)
+            .cloned()
+            .collect()),
+        RemoteClassifierRowSource::SharedDataset { dataset_id } => {
+            let dataset = get_shared_dataset_from_memory(*dataset_id)?;
+            // Predictions are not known yet
+            Ok(dataset.to_data_points(&vec![false; dataset.rows.len()], 0))
+        }
+    }
+}
+
+fn get_http_endpoint(model_data: &ClassifierModelData) -> Option<HttpClassifierEndpoint> {
+    match &model_data.predictor {
+        Some(ClassifierPredictor::Http(endpoint)) => Some(endpoint.clone()),
+        _ => None,
+    }
+}
+
+fn save_remote_evaluation(model_id: u128, evaluation: RemoteClassifierEvaluation) {
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&model_id).expect("Model not found");
+        let mut model_data = get_classifier_model_data(&model);
+
+        let evaluations = model_data.remote_evaluations.get_or_insert_with(Vec::new);
+        if let Some(saved) = evaluations
+            .iter_mut()
+            .find(|e| e.remote_evaluation_id == evaluation.remote_evaluation_id)
+        {
+            *saved = evaluation;
+        }
+
+        model.model_type = ModelType::Classifier(model_data);
+        models.insert(model_id, model);
+    });
+}
+
+/// Sends the feature rows of a classifier to its HTTP prediction endpoint, as a queued job.
+/// The endpoint should be set with `set_classifier_predictor` first.
+/// The returned predictions are saved as data points of the evaluation, and its metrics are calculated when the job finishes.
+/// Results are stored in `remote_evaluations`, apart from the metrics of the uploaded predictions.
+///
+/// # Parameters
+/// - `model_id: u128`: classifier model id.
+/// - `max_queries: usize`: max number of rows to send. If 0, all rows are sent.
+/// - `max_errors: u32`: max number of call errors before the job fails. If 0, there is no limit.
+/// - `counter_factual: bool`: if true, every row is also sent with each sensible attribute flipped.
+/// - `dataset_id: Option<u128>`: if set, the rows of this shared dataset are sent instead of the model data points.
+///
+/// # Returns
+/// - `Result<u128, GenericError>`: the id of the created job.
+#[ic_cdk::update]
+pub fn evaluate_remote_classifier(
+    model_id: u128,
+    max_queries: usize,
+    max_errors: u32,
+    counter_factual: bool,
+    dataset_id: Option<u128>,
+) -> Result<u128, GenericError> {
+    only_admin();
+    check_cycles_before_action();
+    let caller = ic_cdk::api::caller();
+
+    let model = get_model_from_memory(model_id)?;
+    is_owner(&model, caller);
+
+    let model_data = match &model.model_type {
+        ModelType::Classifier(_) => get_classifier_model_data(&model),
+        _ => {
+            return Err(GenericError::new(
+                GenericError::INVALID_MODEL_TYPE,
+                "Model should be a classifier.",
+            ))
+        }
+    };
+
+    let endpoint = get_http_endpoint(&model_data).ok_or(GenericError::new(
+        GenericError::NOT_FOUND,
+        "Model has no HTTP prediction endpoint set. Use set_classifier_predictor first.",
+    ))?;
+
+    let source = match (dataset_id, model_data.data_points.last()) {
+        (Some(dataset_id), _) => RemoteClassifierRowSource::SharedDataset { dataset_id },
+        (None, Some(point)) => RemoteClassifierRowSource::StoredDataPoints {
+            timestamp: point.timestamp,
+        },
+        (None, None) => {
+            return Err(GenericError::new(
+                GenericError::EMPTY_INPUT,
+                "Model has no data points to send.",
+            ))
+        }
+    };
+
+    let rows = remote_evaluation_rows(&model_data, &source)?.len();
+    let target = if max_queries == 0 || max_queries > rows {
+        rows
+    } else {
+        max_queries
+    };
+
+    let job_id = MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&model_id).expect("Model not found");
+        let mut model_data = get_classifier_model_data(&model);
+
+        NEXT_REMOTE_CLASSIFIER_EVALUATION_ID.with(|id| {
+            let mut next_id = id.borrow_mut();
+            let remote_evaluation_id = *next_id.get();
+
+            let job_id = create_job_with_job_type(
+                model_id,
+                JobType::RemoteClassifierEvaluation {
+                    remote_evaluation_id,
+                },
+                target,
+            );
+
+            model_data
+                .remote_evaluations
+                .get_or_insert_with(Vec::new)
+                .push(RemoteClassifierEvaluation {
+                    remote_evaluation_id,
+                    timestamp: ic_cdk::api::time(),
+                    source,
+                    endpoint_url: endpoint.url.clone(),
+                    max_queries,
+                    max_errors,
+                    errors: 0,
+                    call_errors: Vec::new(),
+                    data_points: Vec::new(),
+                    counter_factual,
+                    counter_factual_predictions: Vec::new(),
+                    counter_factual_result: None,
+                    metrics: None,
+                    finished: false,
+                    canceled: false,
+                });
+
+            next_id.set(remote_evaluation_id + 1).unwrap();
+
+            model.model_type = ModelType::Classifier(model_data);
+            models.insert(model_id, model);
+
+            job_id
+        })
+    });
+
+    bootstrap_job_queue();
+
+    Ok(job_id)
+}
+
+fn record_call_error(
+    evaluation: &mut RemoteClassifierEvaluation,
+    row: usize,
+    sensible_attribute: Option<String>,
+    message: String,
+) {
+    evaluation.errors += 1;
+    evaluation.call_errors.push(RemoteClassifierCallError {
+        row,
+        sensible_attribute,
+        message,
+    });
+}
+
+/// Finishes a remote evaluation: calculates its metrics and counter factual results.
+/// They are kept in the evaluation, so the metrics of the uploaded predictions are not replaced.
+fn finish_remote_evaluation(model: &Model, mut evaluation: RemoteClassifierEvaluation, rows: &Vec<DataPoint>) {
+    let dataset = match evaluation.source {
+        RemoteClassifierRowSource::SharedDataset { dataset_id } => {
+            get_shared_dataset_from_memory(dataset_id).ok()
+        }
+        _ => None,
+    };
+
+    let thresholds = match &dataset {
+        Some(dataset) => dataset.thresholds(),
+        None => model
+            .cached_thresholds
+            .as_ref()
+            .and_then(|cached| cached.thresholds.clone()),
+    };
+
+    let timestamp = ic_cdk::api::time();
+    let metrics = metrics_from_data_points(&evaluation.data_points, thresholds.clone(), timestamp);
+
+    if evaluation.counter_factual && !evaluation.data_points.is_empty() {
+        if let Ok(flips) = CounterFactualFlips::from_data_points(rows) {
+            let original: Vec<bool> = evaluation.data_points.iter().map(|dp| dp.predicted).collect();
+            let (change_rate_overall, total_data_points, sensible_attributes) =
+                aggregate_counter_factual_predictions(
+                    &evaluation.data_points,
+                    &flips,
+                    &original,
+                    &evaluation.counter_factual_predictions,
+                    thresholds,
+                );
+            evaluation.counter_factual_result = Some(ClassifierCounterFactualResult {
+                change_rate_overall,
+                total_data_points,
+                sensible_attributes,
+                timestamp,
+            });
+        }
+    }
+
+    evaluation.metrics = Some(metrics.clone());
+    evaluation.finished = true;
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&model.model_id).expect("Model not found");
+        let mut model_data = get_classifier_model_data(&model);
+
+        // Only complete runs over a shared dataset are comparable with other models
+        if let Some(dataset) = &dataset {
+            if evaluation.data_points.len() == dataset.rows.len() {
+                model_data
+                    .dataset_evaluations
+                    .get_or_insert_with(Vec::new)
+                    .push(SharedDatasetEvaluation {
+                        dataset_id: dataset.dataset_id,
+                        timestamp,
+                        metrics: metrics.clone(),
+                    });
+            }
+        }
+
+        if let Some(saved) = model_data
+            .remote_evaluations
+            .get_or_insert_with(Vec::new)
+            .iter_mut()
+            .find(|e| e.remote_evaluation_id == evaluation.remote_evaluation_id)
+        {
+            *saved = evaluation;
+        }
+
+        model.model_type = ModelType::Classifier(model_data);
+        models.insert(model.model_id, model);
+    });
+}
+
+/// Sends the next row of a remote classifier evaluation to the endpoint.
+/// Returns true when the job has finished, false if further calls are required.
+pub async fn process_next_remote_classifier_query(
+    model_id: u128,
+    remote_evaluation_id: u128,
+    job: &Job,
+) -> Result<bool, String> {
+    let model = get_model_from_memory(model_id).map_err(|e| e.to_string())?;
+    let model_data = get_classifier_model_data(&model);
+
+    let evaluation = model_data
+        .remote_evaluations
+        .as_ref()
+        .and_then(|evaluations| {
+            evaluations
+                .iter()
+                .find(|e| e.remote_evaluation_id == remote_evaluation_id)
+                .cloned()
+        });
+
+    let mut evaluation = match evaluation {
+        Some(evaluation) => evaluation,
+        None => {
+            let error = "Invalid id for remote classifier evaluation";
+            ic_cdk::eprintln!("Error: {}", error);
+            internal_job_fail(job.id, model_id, Some(error.to_string()));
+            return Ok(true);
+        }
+    };
+
+    if evaluation.finished || evaluation.canceled {
+        ic_cdk::println!("Remote classifier evaluation already finished. Exiting...");
+        return Ok(true);
+    }
+
+    if job_should_be_stopped(job.id) {
+        ic_cdk::eprintln!("Job has been stopped while running. Marking remote evaluation as finished and cancelled.");
+        internal_job_stop(job.id, model_id);
+        evaluation.canceled = true;
+        evaluation.finished = true;
+        save_remote_evaluation(model_id, evaluation);
+        return Ok(true);
+    }
+
+    let rows = match remote_evaluation_rows(&model_data, &evaluation.source) {
+        Ok(rows) => rows,
+        Err(err) => {
+            internal_job_fail(job.id, model_id, Some(err.message));
+            evaluation.canceled = true;
+            evaluation.finished = true;
+            save_remote_evaluation(model_id, evaluation);
+            return Ok(true);
+        }
+    };
+
+    if job.progress.completed >= job.progress.target {
+        finish_remote_evaluation(&model, evaluation, &rows);
+        internal_job_complete(job.id, model_id);
+        ic_cdk::println!("Job {} is complete.", job.id);
+        return Ok(true);
+    }
+
+    let endpoint = match get_http_endpoint(&model_data) {
+        Some(endpoint) => endpoint,
+        None => {
+            let error = "Model has no HTTP prediction endpoint set";
+            internal_job_fail(job.id, model_id, Some(error.to_string()));
+            evaluation.canceled = true;
+            evaluation.finished = true;
+            save_remote_evaluation(model_id, evaluation);
+            return Ok(true);
+        }
+    };
+
+    let row = match rows.get(job.progress.completed) {
+        Some(row) => row.clone(),
+        None => {
+            let error = "Rows changed while the evaluation was running";
+            internal_job_fail(job.id, model_id, Some(error.to_string()));
+            evaluation.canceled = true;
+            evaluation.finished = true;
+            save_remote_evaluation(model_id, evaluation);
+            return Ok(true);
+        }
+    };
+
+    let row_index = job.progress.completed;
+
+    match call_classifier_endpoint(&endpoint, &row.features).await {
+        Ok(predicted) => {
+            let mut flipped_predictions: Vec<Option<bool>> = Vec::new();
+
+            if evaluation.counter_factual {
+                if let Ok(flips) = CounterFactualFlips::from_data_points(&rows) {
+                    for (attribute, (name, _)) in flips.attributes.iter().enumerate() {
+                        let prediction = match flips.flipped_features(&row.features, attribute) {
+                            Some(features) => match call_classifier_endpoint(&endpoint, &features).await {
+                                Ok(prediction) => Some(prediction),
+                                Err(err) => {
+                                    ic_cdk::eprintln!("Error calling classifier endpoint with {} flipped: {}", name, err);
+                                    record_call_error(&mut evaluation, row_index, Some(name.clone()), err);
+                                    None
+                                }
+                            },
+                            None => None,
+                        };
+                        flipped_predictions.push(prediction);
+                    }
+                }
+            }
+
+            let data_point_id = NEXT_DATA_POINT_ID.with(|id| {
+                let mut id = id.borrow_mut();
+                let current_id = *id.get();
+                id.set(current_id + 1).unwrap();
+                current_id
+            });
+
+            evaluation.data_points.push(DataPoint {
+                data_point_id,
+                target: row.target,
+                privileged_map: row.privileged_map.clone(),
+                predicted,
+                features: row.features.clone(),
+                timestamp: evaluation.timestamp,
+            });
+            evaluation.counter_factual_predictions.push(flipped_predictions);
+        }
+        Err(err) => {
+            ic_cdk::eprintln!("Error calling classifier endpoint: {}", err);
+            record_call_error(&mut evaluation, row_index, None, err);
+        }
+    }
+
+    // max_errors = 0 means there is no limit
+    if evaluation.max_errors != 0 && evaluation.errors > evaluation.max_errors {
+        let last_error = evaluation
+            .call_errors
+            .last()
+            .map(|error| error.message.clone())
+            .unwrap_or_default();
+        internal_job_fail(job.id, model_id, Some(format!("Too many errors. Last error: {}", last_error)));
+        evaluation.canceled = true;
+        evaluation.finished = true;
+        save_remote_evaluation(model_id, evaluation);
+        return Ok(true);
+    }
+
+    let errors = evaluation.errors as usize;
+    save_remote_evaluation(model_id, evaluation);
+
+    internal_job_in_progress(job.id, model_id, job.progress.completed + 1, 0, errors);
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint() -> HttpClassifierEndpoint {
+        HttpClassifierEndpoint {
+            url: "https://example.com/predict".to_string(),
+            features_field: "instances".to_string(),
+            feature_names: None,
+            prediction_pointer: "/predictions/0".to_string(),
+            positive_label: None,
+            threshold: None,
+            api_key_config_key: None,
+        }
+    }
+
+    #[test]
+    fn test_payload_with_feature_array() {
+        let payload = build_classifier_payload(&endpoint(), &[1.0, 0.5]).unwrap();
+        let json: Value = serde_json::from_slice(&payload).unwrap();
+
+        assert_eq!(json, serde_json::json!({"instances": [1.0, 0.5]}));
+    }
+
+    #[test]
+    fn test_payload_with_feature_names() {
+        let mut endpoint = endpoint();
+        endpoint.feature_names = Some(vec!["age".to_string(), "gender".to_string()]);
+
+        let payload = build_classifier_payload(&endpoint, &[30.0, 1.0]).unwrap();
+        let json: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json, serde_json::json!({"instances": {"age": 30.0, "gender": 1.0}}));
+
+        assert!(build_classifier_payload(&endpoint, &[30.0]).is_err());
+    }
+
+    #[test]
+    fn test_prediction_values() {
+        let mut endpoint = endpoint();
+
+        assert_eq!(prediction_from_value(&endpoint, &serde_json::json!(true)), Ok(true));
+        assert_eq!(prediction_from_value(&endpoint, &serde_json::json!(0.7)), Ok(true));
+        assert_eq!(prediction_from_value(&endpoint, &serde_json::json!(0)), Ok(false));
+        assert_eq!(prediction_from_value(&endpoint, &serde_json::json!("false")), Ok(false));
+        assert!(prediction_from_value(&endpoint, &serde_json::json!("approved")).is_err());
+
+        endpoint.positive_label = Some("approved".to_string());
+        assert_eq!(prediction_from_value(&endpoint, &serde_json::json!("approved")), Ok(true));
+        assert_eq!(prediction_from_value(&endpoint, &serde_json::json!("denied")), Ok(false));
+    }
+
+    #[test]
+    fn test_extract_prediction_value() {
+        let body = br#"{"id": "abc", "predictions": [1, 0]}"#;
+
+        assert_eq!(
+            extract_prediction_value(body, "/predictions/0"),
+            Ok(serde_json::json!(1))
+        );
+        assert!(extract_prediction_value(body, "/label").is_err());
+        assert!(extract_prediction_value(b"not json", "/label").is_err());
+    }
+}
@@ -0,0 +1,350 @@
+use crate::llm_fairness::counter_factual_changed;
+use crate::metrics_calculation::metrics_from_data_points;
+use crate::types::{
+    AdditionalSensibleAttribute, CounterFactualModelEvaluationResult, DataPoint, LLMDataPoint,
+    LLMDataPointCounterFactual, SensibleAttributeEvaluationResult,
+};
+use std::collections::HashMap;
+
+/// Max number of additional sensible attributes of a LLM fairness dataset.
+/// Every combination of sensible attributes adds a counter factual call for every test row.
+pub const MAX_ADDITIONAL_SENSIBLE_ATTRIBUTES: usize = 2;
+
+/// Swapped attributes of a counter factual prompt, and the (column, swapped value) pairs
+pub type SensibleAttributeSwap = (Vec<String>, Vec<(String, String)>);
+
+/// Binarizes the additional sensible attributes of a row: 1.0 for the privileged group and 0.0 for the rest
+pub fn binarize_additional_sensible_attributes(
+    row: &HashMap<String, String>,
+    attributes: &[AdditionalSensibleAttribute],
+) -> Result<Vec<f64>, String> {
+    attributes
+        .iter()
+        .map(|attribute| {
+            let value = row
+                .get(&attribute.name)
+                .map(|value| value.trim())
+                .ok_or_else(|| format!("Sensible attribute '{}' not found in row", attribute.name))?;
+            let privileged = attribute.privileged_values.iter().any(|v| v == value);
+            Ok(if privileged { 1.0 } else { 0.0 })
+        })
+        .collect()
+}
+
+/// Returns every combination of `attributes` sensible attributes as indices, shortest first.
+/// Index 0 is the main sensible attribute of the dataset.
+pub fn sensible_attribute_combinations(attributes: usize) -> Vec<Vec<usize>> {
+    let mut combinations: Vec<Vec<usize>> = (1..(1usize << attributes))
+        .map(|mask| (0..attributes).filter(|i| mask & (1 << i) != 0).collect())
+        .collect();
+    combinations.sort_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)));
+    combinations
+}
+
+/// Returns the value a sensible attribute is swapped to in counter factual prompts, if it can be swapped
+fn swapped_value(
+    row: &HashMap<String, String>,
+    attribute: &AdditionalSensibleAttribute,
+) -> Option<String> {
+    let value = row.get(&attribute.name)?.trim();
+    if !attribute.counter_factual_values.iter().any(|v| v == value) {
+        return None;
+    }
+
+    attribute
+        .counter_factual_values
+        .iter()
+        .find(|v| *v != value)
+        .cloned()
+}
+
+/// Returns the swaps of the counter factual prompts of a row that change additional sensible attributes.
+/// There is one for every combination of sensible attributes except the main attribute alone,
+/// which is the main counter factual of the data point. Combinations with an attribute that
+/// cannot be swapped in this row are left out.
+///
+/// # Parameters
+/// - `row`: test row.
+/// - `sensible_attribute`: column of the main sensible attribute.
+/// - `sensible_attribute_swap`: value the main sensible attribute is swapped to, if it can be swapped.
+/// - `attributes`: additional sensible attributes of the dataset.
+///
+/// # Returns
+/// - One swap for every combination.
+pub fn sensible_attribute_swaps(
+    row: &HashMap<String, String>,
+    sensible_attribute: &str,
+    sensible_attribute_swap: Option<&str>,
+    attributes: &[AdditionalSensibleAttribute],
+) -> Vec<SensibleAttributeSwap> {
+    let swaps: Vec<Option<(String, String)>> = std::iter::once(
+        sensible_attribute_swap.map(|value| (sensible_attribute.to_string(), value.to_string())),
+    )
+    .chain(attributes.iter().map(|attribute| {
+        swapped_value(row, attribute).map(|value| (attribute.name.clone(), value))
+    }))
+    .collect();
+
+    sensible_attribute_combinations(swaps.len())
+        .into_iter()
+        .filter(|combination| *combination != [0])
+        .filter_map(|combination| {
+            let combination_swaps: Vec<(String, String)> = combination
+                .iter()
+                .map(|i| swaps[*i].clone())
+                .collect::<Option<Vec<(String, String)>>>()?;
+            let swapped_attributes = combination_swaps.iter().map(|(name, _)| name.clone()).collect();
+            Some((swapped_attributes, combination_swaps))
+        })
+        .collect()
+}
+
+/// Returns true if the data point is privileged in all the attributes of the combination,
+/// false if it's unprivileged in all of them, and None otherwise.
+/// Intersections only compare these two groups, so data points with mixed groups are left out.
+fn intersection_group(features: &[f64], combination: &[usize]) -> Option<bool> {
+    let mut privileged = combination.iter().map(|i| features.get(*i).map(|value| *value == 1.0));
+    let first = privileged.next()??;
+    for value in privileged {
+        if value? != first {
+            return None;
+        }
+    }
+    Some(first)
+}
+
+/// Returns the counter factual of a data point that swaps the attributes of the combination
+fn combination_counter_factual<'a>(
+    data_point: &'a LLMDataPoint,
+    combination: &[usize],
+    attributes: &[String],
+) -> Option<&'a LLMDataPointCounterFactual> {
+    if combination == [0] {
+        return data_point.counter_factual.as_ref();
+    }
+
+    data_point
+        .sensible_attribute_counter_factuals
+        .iter()
+        .flatten()
+        .find(|counter_factual| counter_factual.attributes == attributes)
+        .map(|counter_factual| &counter_factual.counter_factual)
+}
+
+/// Calculates the counter factual change rates of a combination of sensible attributes
+fn combination_counter_factual_metrics(
+    llm_data_points: &[LLMDataPoint],
+    combination: &[usize],
+    attributes: &[String],
+) -> CounterFactualModelEvaluationResult {
+    // Index 0 is the unprivileged group and index 1 the privileged one
+    let mut totals = [0u32; 2];
+    let mut changed = [0u32; 2];
+
+    for data_point in llm_data_points.iter().filter(|data_point| !data_point.error) {
+        let group = match intersection_group(&data_point.features, combination) {
+            Some(group) => group as usize,
+            None => continue,
+        };
+        let counter_factual = match combination_counter_factual(data_point, combination, attributes) {
+            Some(counter_factual) if !counter_factual.error => counter_factual,
+            _ => continue,
+        };
+
+        totals[group] += 1;
+        if counter_factual_changed(data_point, counter_factual) {
+            changed[group] += 1;
+        }
+    }
+
+    let rate = |changed: u32, total: u32| -> f32 {
+        if total == 0 {
+            0.0
+        } else {
+            changed as f32 / total as f32
+        }
+    };
+
+    CounterFactualModelEvaluationResult {
+        change_rate_overall: rate(changed[0] + changed[1], totals[0] + totals[1]),
+        change_rate_sensible_attributes: vec![rate(changed[0], totals[0]), rate(changed[1], totals[1])],
+        total_sensible_attributes: totals.to_vec(),
+        sensible_attribute: attributes.join(" & "),
+        change_rate_value_pairs: None,
+    }
+}
+
+/// Calculates the metrics and counter factual change rates of every sensible attribute,
+/// and of every intersection of sensible attributes.
+///
+/// # Parameters
+/// - `llm_data_points`: data points, whose features are the binarized sensible attributes.
+/// - `sensible_attributes`: names of the sensible attributes, in the features order.
+/// - `timestamp`: timestamp of the metrics.
+pub fn calculate_sensible_attribute_results(
+    llm_data_points: &[LLMDataPoint],
+    sensible_attributes: &[String],
+    timestamp: u64,
+) -> Vec<SensibleAttributeEvaluationResult> {
+    sensible_attribute_combinations(sensible_attributes.len())
+        .into_iter()
+        .map(|combination| {
+            let attributes: Vec<String> = combination
+                .iter()
+                .map(|i| sensible_attributes[*i].clone())
+                .collect();
+            let variable_name = attributes.join(" & ");
+
+            let data_points: Vec<DataPoint> = llm_data_points
+                .iter()
+                .filter_map(|llm_data_point| {
+                    let group = intersection_group(&llm_data_point.features, &combination)?;
+                    let mut data_point =
+                        llm_data_point.to_data_point(HashMap::from([(variable_name.clone(), 0)]))?;
+                    data_point.features = vec![if group { 1.0 } else { 0.0 }];
+                    Some(data_point)
+                })
+                .collect();
+            let metrics = metrics_from_data_points(
+                &data_points,
+                Some(HashMap::from([(variable_name, (0.5, true))])),
+                timestamp,
+            );
+
+            SensibleAttributeEvaluationResult {
+                counter_factual: combination_counter_factual_metrics(llm_data_points, &combination, &attributes),
+                attributes,
+                metrics,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::SensibleAttributesCounterFactual;
+
+    fn race() -> AdditionalSensibleAttribute {
+        AdditionalSensibleAttribute {
+            name: "race".to_string(),
+            privileged_values: vec!["White".to_string()],
+            counter_factual_values: vec!["White".to_string(), "Black".to_string()],
+        }
+    }
+
+    fn counter_factual(predicted: bool) -> LLMDataPointCounterFactual {
+        LLMDataPointCounterFactual {
+            prompt: None,
+            response: None,
+            valid: true,
+            error: false,
+            target: true,
+            timestamp: 0,
+            predicted: Some(predicted),
+            features: Vec::new(),
+            sensible_attribute_value: None,
+            answer_extraction: None,
+        }
+    }
+
+    fn data_point(features: [f64; 2], target: bool, predicted: bool, race_counter_factual: bool) -> LLMDataPoint {
+        LLMDataPoint {
+            data_point_id: 0,
+            target,
+            predicted: Some(predicted),
+            features: features.to_vec(),
+            timestamp: 0,
+            prompt: String::new(),
+            response: None,
+            valid: true,
+            error: false,
+            counter_factual: Some(counter_factual(predicted)),
+            sensible_attribute_value: None,
+            additional_counter_factuals: None,
+            answer_extraction: None,
+            sensible_attribute_counter_factuals: Some(vec![SensibleAttributesCounterFactual {
+                attributes: vec!["race".to_string()],
+                counter_factual: counter_factual(race_counter_factual),
+            }]),
+        }
+    }
+
+    #[test]
+    fn test_combinations() {
+        assert_eq!(
+            sensible_attribute_combinations(3),
+            vec![vec![0], vec![1], vec![2], vec![0, 1], vec![0, 2], vec![1, 2], vec![0, 1, 2]]
+        );
+    }
+
+    #[test]
+    fn test_swaps() {
+        let row = HashMap::from([
+            ("male".to_string(), "1".to_string()),
+            ("race".to_string(), "White".to_string()),
+        ]);
+        let swaps = sensible_attribute_swaps(&row, "male", Some("0"), &[race()]);
+
+        assert_eq!(
+            swaps,
+            vec![
+                (vec!["race".to_string()], vec![("race".to_string(), "Black".to_string())]),
+                (
+                    vec!["male".to_string(), "race".to_string()],
+                    vec![
+                        ("male".to_string(), "0".to_string()),
+                        ("race".to_string(), "Black".to_string()),
+                    ]
+                ),
+            ]
+        );
+
+        // Values out of the counter factual values are not swapped
+        let row = HashMap::from([
+            ("male".to_string(), "1".to_string()),
+            ("race".to_string(), "Asian".to_string()),
+        ]);
+        assert!(sensible_attribute_swaps(&row, "male", Some("0"), &[race()]).is_empty());
+        assert_eq!(binarize_additional_sensible_attributes(&row, &[race()]), Ok(vec![0.0]));
+    }
+
+    #[test]
+    fn test_results_per_attribute_and_intersection() {
+        // Features are [male, race]
+        let data_points = vec![
+            data_point([1.0, 1.0], true, true, true),
+            data_point([1.0, 1.0], false, false, true),
+            data_point([1.0, 0.0], true, true, false),
+            data_point([0.0, 1.0], false, true, true),
+            data_point([0.0, 0.0], true, true, false),
+            data_point([0.0, 0.0], false, false, false),
+            data_point([0.0, 0.0], true, false, true),
+        ];
+        let attributes = vec!["male".to_string(), "race".to_string()];
+        let results = calculate_sensible_attribute_results(&data_points, &attributes, 1);
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].attributes, vec!["male".to_string()]);
+        assert_eq!(results[1].attributes, vec!["race".to_string()]);
+        assert_eq!(results[2].attributes, attributes);
+
+        // race: privileged 2/3 positive, unprivileged 2/4 positive
+        let spd = results[1].metrics.statistical_parity_difference.as_ref().unwrap();
+        assert_eq!(spd[0].variable_name, "race");
+        assert!((spd[0].value - (0.5 - 2.0 / 3.0)).abs() < 1e-6);
+        // race counter factual changes the prediction of the data points 2, 3, 5 and 7
+        assert_eq!(results[1].counter_factual.total_sensible_attributes, vec![4, 3]);
+        assert!((results[1].counter_factual.change_rate_sensible_attributes[0] - 0.75).abs() < 1e-6);
+        assert!((results[1].counter_factual.change_rate_overall - 4.0 / 7.0).abs() < 1e-6);
+
+        // The intersection only compares the first two data points with the last three
+        let spd = results[2].metrics.statistical_parity_difference.as_ref().unwrap();
+        assert_eq!(spd[0].variable_name, "male & race");
+        assert!((spd[0].value - (1.0 / 3.0 - 0.5)).abs() < 1e-6);
+        // There are no counter factuals swapping both attributes
+        assert_eq!(results[2].counter_factual.total_sensible_attributes, vec![0, 0]);
+        assert_eq!(results[2].counter_factual.change_rate_overall, 0.0);
+        assert_eq!(results[2].counter_factual.change_rate_sensible_attributes, vec![0.0, 0.0]);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::errors::GenericError;
+use crate::metrics_calculation::metrics_from_data_points;
+use crate::types::{
+    get_classifier_model_data, DatasetLeaderboardEntry, Metrics, ModelType, SensitiveColumn,
+    SharedDataset, SharedDatasetEvaluation, SharedDatasetRow,
+};
+use crate::{
+    check_cycles_before_action, get_model_from_memory, is_owner, only_admin, DATASETS, MODELS,
+    NEXT_DATASET_ID, NEXT_DATA_POINT_ID,
+};
+use candid::Principal;
+
+pub(crate) fn get_shared_dataset_from_memory(dataset_id: u128) -> Result<SharedDataset, GenericError> {
+    DATASETS
+        .with(|datasets| datasets.borrow().get(&dataset_id))
+        .ok_or(GenericError::new(
+            GenericError::NOT_FOUND,
+            "Dataset not found",
+        ))
+}
+
+/// Saves the predictions of a classifier over a shared dataset.
+/// Predictions are added as new data points of the model, and metrics are calculated over them.
+/// Predictions should be aligned with the dataset rows.
+pub(crate) fn record_dataset_predictions(
+    model_id: u128,
+    dataset: &SharedDataset,
+    predictions: &[bool],
+) -> Metrics {
+    let timestamp = ic_cdk::api::time();
+
+    let mut data_points = dataset.to_data_points(predictions, timestamp);
+    NEXT_DATA_POINT_ID.with(|id| {
+        let mut id = id.borrow_mut();
+        for data_point in data_points.iter_mut() {
+            let current_id = *id.get();
+            data_point.data_point_id = current_id;
+            id.set(current_id + 1).unwrap();
+        }
+    });
+
+    let metrics = metrics_from_data_points(&data_points, dataset.thresholds(), timestamp);
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        let mut model = models.get(&model_id).expect("Model not found");
+        let mut model_data = get_classifier_model_data(&model);
+
+        model_data.data_points.append(&mut data_points);
+        model_data.metrics = metrics.clone();
+        model_data.metrics_history.push(metrics.clone());
+        model_data
+            .dataset_evaluations
+            .get_or_insert_with(Vec::new)
+            .push(SharedDatasetEvaluation {
+                dataset_id: dataset.dataset_id,
+                timestamp,
+                metrics: metrics.clone(),
+            });
+
+        model.model_type = ModelType::Classifier(model_data);
+        models.insert(model_id, model);
+    });
+
+    metrics
+}
+
+/// Creates a shared benchmark dataset, that can be used by several classifier models.
+///
+/// # Parameters
+/// - `name: String`: dataset name.
+/// - `description: String`: dataset description.
+/// - `schema: Vec<String>`: name of every feature column.
+/// - `target_name: String`: name of the target column.
+/// - `sensitive_columns: Vec<SensitiveColumn>`: sensitive columns definition. Indexes refer to the features vector.
+/// - `features: Vec<Vec<f64>>`: one vector of features for every row.
+/// - `labels: Vec<bool>`: target for every row.
+///
+/// # Returns
+/// - `Result<u128, GenericError>`: the dataset id.
+#[ic_cdk::update]
+pub fn create_shared_dataset(
+    name: String,
+    description: String,
+    schema: Vec<String>,
+    target_name: String,
+    sensitive_columns: Vec<SensitiveColumn>,
+    features: Vec<Vec<f64>>,
+    labels: Vec<bool>,
+) -> Result<u128, GenericError> {
+    only_admin();
+    check_cycles_before_action();
+
+    if name.trim().is_empty() {
+        return Err(GenericError::new(
+            GenericError::EMPTY_INPUT,
+            "Dataset name cannot be empty.",
+        ));
+    }
+    if features.is_empty() {
+        return Err(GenericError::new(
+            GenericError::EMPTY_INPUT,
+            "Dataset should have at least one row.",
+        ));
+    }
+    if features.len() != labels.len() {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            "Lengths of features and labels must be equal.",
+        ));
+    }
+    if features.iter().any(|row| row.len() != schema.len()) {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            "Every row should have one value for every column in the schema.",
+        ));
+    }
+    if sensitive_columns.is_empty() {
+        return Err(GenericError::new(
+            GenericError::EMPTY_INPUT,
+            "Dataset should have at least one sensitive column.",
+        ));
+    }
+    if let Some(column) = sensitive_columns
+        .iter()
+        .find(|column| column.feature_index as usize >= schema.len())
+    {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            format!("Sensitive column {} is out of bounds.", column.name),
+        ));
+    }
+
+    let caller: Principal = ic_cdk::api::caller();
+
+    let rows: Vec<SharedDatasetRow> = features
+        .into_iter()
+        .zip(labels)
+        .enumerate()
+        .map(|(row_id, (features, target))| SharedDatasetRow {
+            row_id: row_id as u128,
+            features,
+            target,
+        })
+        .collect();
+
+    let dataset_id = NEXT_DATASET_ID.with(|id| {
+        let mut id = id.borrow_mut();
+        let current_id = *id.get();
+        id.set(current_id + 1).unwrap();
+        current_id
+    });
+
+    DATASETS.with(|datasets| {
+        datasets.borrow_mut().insert(
+            dataset_id,
+            SharedDataset {
+                dataset_id,
+                name,
+                description,
+                owner: caller,
+                schema,
+                target_name,
+                sensitive_columns,
+                rows,
+                timestamp: ic_cdk::api::time(),
+            },
+        );
+    });
+
+    Ok(dataset_id)
+}
+
+/// Deletes a shared dataset. Only the owner can delete it.
+/// Evaluations already saved in models are kept.
+#[ic_cdk::update]
+pub fn delete_shared_dataset(dataset_id: u128) -> Result<(), GenericError> {
+    check_cycles_before_action();
+
+    let dataset = get_shared_dataset_from_memory(dataset_id)?;
+    if dataset.owner != ic_cdk::api::caller() {
+        ic_cdk::api::trap("Unauthorized: caller is not the dataset owner");
+    }
+
+    DATASETS.with(|datasets| datasets.borrow_mut().remove(&dataset_id));
+
+    Ok(())
+}
+
+/// Returns a shared dataset, including its rows
+#[ic_cdk::query]
+pub fn get_shared_dataset(dataset_id: u128) -> Result<SharedDataset, GenericError> {
+    check_cycles_before_action();
+    get_shared_dataset_from_memory(dataset_id)
+}
+
+/// Returns all shared datasets, without rows
+#[ic_cdk::query]
+pub fn get_shared_datasets() -> Vec<SharedDataset> {
+    check_cycles_before_action();
+
+    DATASETS.with(|datasets| {
+        datasets
+            .borrow()
+            .values()
+            .map(|dataset| dataset.prune())
+            .collect()
+    })
+}
+
+/// Attaches the predictions of a classifier model to the rows of a shared dataset.
+///
+/// # Parameters
+/// - `model_id: u128`: classifier model id.
+/// - `dataset_id: u128`: shared dataset id.
+/// - `predictions: Vec<bool>`: one prediction for every dataset row, in the same order.
+///
+/// # Returns
+/// - `Result<Metrics, GenericError>`: metrics over the dataset rows, which also become the model metrics.
+#[ic_cdk::update]
+pub fn attach_dataset_predictions(
+    model_id: u128,
+    dataset_id: u128,
+    predictions: Vec<bool>,
+) -> Result<Metrics, GenericError> {
+    check_cycles_before_action();
+    let caller = ic_cdk::api::caller();
+
+    let model = get_model_from_memory(model_id)?;
+    is_owner(&model, caller);
+
+    if !matches!(model.model_type, ModelType::Classifier(_)) {
+        return Err(GenericError::new(
+            GenericError::INVALID_MODEL_TYPE,
+            "Model should be a classifier.",
+        ));
+    }
+
+    let dataset = get_shared_dataset_from_memory(dataset_id)?;
+
+    if predictions.len() != dataset.rows.len() {
+        return Err(GenericError::new(
+            GenericError::INVALID_ARGUMENT,
+            format!(
+                "Expected {} predictions, one for every dataset row.",
+                dataset.rows.len()
+            ),
+        ));
+    }
+
+    Ok(record_dataset_predictions(model_id, &dataset, &predictions))
+}
+
+/// Returns the last evaluation of every classifier model over a shared dataset.
+/// Entries are sorted by the absolute value of the average statistical parity difference, from lower (fairer).
+/// Entries without it go last.
+#[ic_cdk::query]
+pub fn get_dataset_leaderboard(dataset_id: u128) -> Result<Vec<DatasetLeaderboardEntry>, GenericError> {
+    check_cycles_before_action();
+
+    // Checks the dataset exists
+    get_shared_dataset_from_memory(dataset_id)?;
+
+    let mut entries: Vec<DatasetLeaderboardEntry> = MODELS.with(|models| {
+        models
+            .borrow()
+            .values()
+            .filter_map(|model| match model.model_type {
+                ModelType::Classifier(ref model_data) => model_data
+                    .dataset_evaluations
+                    .as_ref()
+                    .and_then(|evaluations| {
+                        evaluations
+                            .iter()
+                            .filter(|evaluation| evaluation.dataset_id == dataset_id)
+                            .max_by_key(|evaluation| evaluation.timestamp)
+                    })
+                    .map(|evaluation| DatasetLeaderboardEntry {
+                        model_id: model.model_id,
+                        model_name: model.model_name.clone(),
+                        timestamp: evaluation.timestamp,
+                        metrics: evaluation.metrics.clone(),
+                    }),
+                _ => None,
+            })
+            .collect()
+    });
+
+    entries.sort_by(|a, b| {
+        let a_spd = a.metrics.average_metrics.statistical_parity_difference.map(f32::abs);
+        let b_spd = b.metrics.average_metrics.statistical_parity_difference.map(f32::abs);
+        match (a_spd, b_spd) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        }
+    });
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dataset() -> SharedDataset {
+        SharedDataset {
+            dataset_id: 1,
+            name: "benchmark".to_string(),
+            description: String::new(),
+            owner: Principal::anonymous(),
+            schema: vec!["gender".to_string(), "age".to_string()],
+            target_name: "approved".to_string(),
+            sensitive_columns: vec![
+                SensitiveColumn {
+                    name: "gender".to_string(),
+                    feature_index: 0,
+                    threshold: None,
+                    greater_than: true,
+                },
+                SensitiveColumn {
+                    name: "age".to_string(),
+                    feature_index: 1,
+                    threshold: Some(25.0),
+                    greater_than: true,
+                },
+            ],
+            rows: vec![
+                SharedDatasetRow { row_id: 0, features: vec![1.0, 30.0], target: true },
+                SharedDatasetRow { row_id: 1, features: vec![0.0, 20.0], target: false },
+            ],
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_thresholds_only_include_defined_ones() {
+        let thresholds = dataset().thresholds().unwrap();
+
+        assert_eq!(thresholds.len(), 1);
+        assert_eq!(thresholds.get("age"), Some(&(25.0, true)));
+
+        let mut without_thresholds = dataset();
+        without_thresholds.sensitive_columns[1].threshold = None;
+        assert_eq!(without_thresholds.thresholds(), None);
+    }
+
+    #[test]
+    fn test_rows_to_data_points() {
+        let data_points = dataset().to_data_points(&[false, true], 7);
+
+        assert_eq!(data_points.len(), 2);
+        assert!(data_points[1].predicted);
+        assert!(!data_points[1].target);
+        assert_eq!(data_points[1].timestamp, 7);
+        assert_eq!(data_points[0].privileged_map.get("age"), Some(&1));
+    }
+}
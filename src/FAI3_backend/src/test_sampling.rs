@@ -0,0 +1,120 @@
+use crate::utils::seeded_vector_shuffle;
+use std::collections::{BTreeMap, HashMap};
+
+/// Selects test rows stratified by sensible attribute and label, reproducibly from the seed.
+///
+/// Rows are grouped by every (sensible value, label) pair, and every group is shuffled.
+/// Then rows are taken from the groups in turn, so short runs have a similar number of rows
+/// of every group. Once a group runs out of rows, the rest of the groups keep being used.
+///
+/// # Parameters
+/// - `records`: test rows.
+/// - `sensible_column`: column of the sensible attribute used to calculate the metrics.
+/// - `predict_attribute`: column with the true label.
+/// - `max_queries`: number of rows to select. If it's 0 or bigger than the number of rows, all the rows are selected.
+/// - `seed`: seed for the shuffling.
+///
+/// # Returns
+/// - Indices of the selected rows in `records`, in the order they should be run.
+pub fn stratified_test_rows(
+    records: &[HashMap<String, String>],
+    sensible_column: &str,
+    predict_attribute: &str,
+    max_queries: usize,
+    seed: u32,
+) -> Vec<usize> {
+    let mut strata: BTreeMap<(String, String), Vec<usize>> = BTreeMap::new();
+    for (i, record) in records.iter().enumerate() {
+        let key = (
+            record.get(sensible_column).map(|v| v.trim().to_string()).unwrap_or_default(),
+            record.get(predict_attribute).map(|v| v.trim().to_string()).unwrap_or_default(),
+        );
+        strata.entry(key).or_default().push(i);
+    }
+
+    let strata: Vec<Vec<usize>> = strata
+        .into_values()
+        .enumerate()
+        .map(|(i, rows)| seeded_vector_shuffle(rows, seed.wrapping_add(i as u32)))
+        .collect();
+
+    let target = if max_queries == 0 || max_queries > records.len() {
+        records.len()
+    } else {
+        max_queries
+    };
+
+    let mut selected = Vec::with_capacity(target);
+    let mut round = 0;
+    while selected.len() < target {
+        for rows in &strata {
+            if selected.len() == target {
+                break;
+            }
+            if let Some(row) = rows.get(round) {
+                selected.push(*row);
+            }
+        }
+        round += 1;
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<HashMap<String, String>> {
+        // 8 rows of group 0 with label L, and 2 of every other cell
+        let mut cells = vec![("0", "L"); 8];
+        cells.extend([("0", "H"), ("0", "H"), ("1", "L"), ("1", "L"), ("1", "H"), ("1", "H")]);
+        cells
+            .into_iter()
+            .map(|(sex, label)| {
+                HashMap::from([
+                    ("sex".to_string(), sex.to_string()),
+                    ("label".to_string(), label.to_string()),
+                ])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_short_runs_are_balanced() {
+        let records = records();
+        let rows = stratified_test_rows(&records, "sex", "label", 8, 42);
+
+        assert_eq!(rows.len(), 8);
+        for (sex, label) in [("0", "L"), ("0", "H"), ("1", "L"), ("1", "H")] {
+            let count = rows
+                .iter()
+                .filter(|row| records[**row]["sex"] == sex && records[**row]["label"] == label)
+                .count();
+            assert_eq!(count, 2);
+        }
+    }
+
+    #[test]
+    fn test_all_rows_once() {
+        let records = records();
+        let mut rows = stratified_test_rows(&records, "sex", "label", 0, 42);
+
+        rows.sort();
+        assert_eq!(rows, (0..records.len()).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_reproducible_from_seed() {
+        let records = records();
+
+        assert_eq!(
+            stratified_test_rows(&records, "sex", "label", 6, 1),
+            stratified_test_rows(&records, "sex", "label", 6, 1)
+        );
+        assert_ne!(
+            stratified_test_rows(&records, "sex", "label", 16, 1),
+            stratified_test_rows(&records, "sex", "label", 16, 2)
+        );
+    }
+}
@@ -80,6 +80,40 @@ pub enum JobType {
     AverageFairness {
         job_dependencies: Vec<u128>,
     },
+    RemoteClassifierEvaluation {
+        remote_evaluation_id: u128,
+    },
+    MultiSeedAggregation {
+        multi_seed_evaluation_id: u128,
+        job_dependencies: Vec<u128>,
+    },
+    PersonaBias {
+        persona_bias_evaluation_id: u128,
+    },
+    BBQ {
+        bbq_evaluation_id: u128,
+    },
+    CrowsPairs {
+        crows_pairs_test_id: u128,
+    },
+    WinoBias {
+        winobias_evaluation_id: u128,
+    },
+    OpenGeneration {
+        open_generation_evaluation_id: u128,
+    },
+    PromptRobustnessAggregation {
+        prompt_robustness_evaluation_id: u128,
+        job_dependencies: Vec<u128>,
+    },
+    // Id of the new evaluation, which has the original id in `rescored_from`
+    LLMFairnessRescoring {
+        model_evaluation_id: u128,
+    },
+    // Id of the new metrics bag, which has the original id in `rescored_from`
+    ContextAssociationTestRescoring {
+        metrics_bag_id: u128,
+    },
     Unassigned, // used for now for jobs without type
 }
 
@@ -103,6 +137,9 @@ pub struct LLMDataPointCounterFactual {
     pub timestamp: u64,
     pub predicted: Option<bool>,
     pub features: Vec<f64>,
+    // Value the sensible attribute was swapped to
+    pub sensible_attribute_value: Option<String>,
+    pub answer_extraction: Option<AnswerExtractionTrace>,
 }
 
 // Represents a data point for using LLMs as classifiers
@@ -112,6 +149,7 @@ pub struct LLMDataPoint {
     pub data_point_id: u128,
     pub target: bool,
     pub predicted: Option<bool>,
+    // Binarized sensible attributes: the main one first, then the additional ones of the dataset
     pub features: Vec<f64>,
     pub timestamp: u64,
     pub prompt: String,
@@ -119,6 +157,57 @@ pub struct LLMDataPoint {
     pub valid: bool,
     pub error: bool,
     pub counter_factual: Option<LLMDataPointCounterFactual>,
+    // Original value of the sensible attribute
+    pub sensible_attribute_value: Option<String>,
+    // Counter factuals for the rest of alternative values, when the sensible attribute has more than two
+    pub additional_counter_factuals: Option<Vec<LLMDataPointCounterFactual>>,
+    // How the answer was extracted from the response
+    pub answer_extraction: Option<AnswerExtractionTrace>,
+    // Counter factuals swapping the additional sensible attributes, alone or together with other sensible attributes
+    pub sensible_attribute_counter_factuals: Option<Vec<SensibleAttributesCounterFactual>>,
+}
+
+/// Counter factual of a LLM data point that swaps one or more sensible attributes at the same time
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct SensibleAttributesCounterFactual {
+    // Swapped attributes, in the dataset order
+    pub attributes: Vec<String>,
+    pub counter_factual: LLMDataPointCounterFactual,
+}
+
+/// How the answer is extracted from a LLM response
+#[derive(Serialize, CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub enum AnswerExtractionStrategy {
+    // The whole cleaned response should be one of the values
+    ExactMatch,
+    // First word of the response that is one of the values
+    FirstMatchingToken,
+    // First capture group of the pattern (or the whole match, if it has no groups)
+    Regex(String),
+    // Field of the first JSON object in the response
+    JsonField(String),
+    // Last non-empty line of the response
+    LastLine,
+}
+
+/// Generation parameters sent to the LLM. Fields that are not set use the evaluation or provider defaults
+#[derive(Serialize, CandidType, CandidDeserialize, Clone, Debug, PartialEq, Default)]
+pub struct GenerationParameters {
+    pub max_new_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    pub stop: Option<Vec<String>>,
+    pub do_sample: Option<bool>,
+}
+
+/// Trace of the answer extraction, so parser failures can be told apart from model failures
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct AnswerExtractionTrace {
+    pub strategy: AnswerExtractionStrategy,
+    // Text the strategy was applied to
+    pub input: String,
+    // Text extracted by the strategy, before comparing it with the expected values
+    pub extracted: Option<String>,
+    pub error: Option<String>,
 }
 
 impl LLMDataPoint {
@@ -161,12 +250,32 @@ impl KeyValuePair {
     }
 }
 
+/// Change rate when swapping the sensible attribute from one value to another
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct CounterFactualValuePairResult {
+    pub original_value: String,
+    pub counter_factual_value: String,
+    pub change_rate: f32,
+    pub total: u32,
+}
+
 #[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
 pub struct CounterFactualModelEvaluationResult {
     pub change_rate_overall: f32,
     pub change_rate_sensible_attributes: Vec<f32>,
     pub total_sensible_attributes: Vec<u32>,
     pub sensible_attribute: String,
+    pub change_rate_value_pairs: Option<Vec<CounterFactualValuePairResult>>,
+}
+
+/// Metrics of a sensible attribute, or of the intersection of several sensible attributes
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct SensibleAttributeEvaluationResult {
+    // With more than one attribute, the privileged group is privileged in all of them,
+    // the unprivileged group is unprivileged in all of them, and the rest of data points are left out
+    pub attributes: Vec<String>,
+    pub metrics: Metrics,
+    pub counter_factual: CounterFactualModelEvaluationResult,
 }
 
 #[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
@@ -190,7 +299,281 @@ pub struct ModelEvaluationResult {
     pub counter_factual: Option<CounterFactualModelEvaluationResult>,
     pub finished: bool,
     pub canceled: bool,
-    pub job_id: Option<u128>, 
+    pub job_id: Option<u128>,
+    // None for evaluations created before the source could be chosen, which used Swap
+    pub counter_factual_source: Option<CounterFactualSource>,
+    // Name of the template library entry used, if any
+    pub prompt_template_name: Option<String>,
+    // None for evaluations created before it could be configured, which used 4 balanced examples
+    pub few_shot: Option<FewShotConfiguration>,
+    // None for evaluations created before it could be configured, which used ExactMatch
+    pub answer_extraction: Option<AnswerExtractionStrategy>,
+    // Id of the evaluation whose stored responses were re-scored to create this one
+    pub rescored_from: Option<u128>,
+    // Effective generation parameters. None for evaluations created before they were stored
+    pub generation_parameters: Option<GenerationParameters>,
+    // None for evaluations created before it could be chosen, which used Sequential
+    pub test_row_sampling: Option<TestRowSampling>,
+    // Indices of the test rows to run, in order. Only set for Stratified sampling
+    pub sampled_test_rows: Option<Vec<usize>>,
+    // Sensible attributes evaluated together with the main one. None if only the main one is evaluated
+    pub additional_sensible_attributes: Option<Vec<AdditionalSensibleAttribute>>,
+    // Sensible attributes of the data point features, in order. Only set when there are additional sensible attributes
+    pub sensible_attributes: Option<Vec<String>>,
+    // Metrics of every sensible attribute and of their intersections
+    pub sensible_attribute_results: Option<Vec<SensibleAttributeEvaluationResult>>,
+}
+
+/// How the test rows of a LLM fairness evaluation are picked
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub enum TestRowSampling {
+    // Test rows in file order
+    Sequential,
+    // Test rows taken in turn from every (sensible value, label) group, shuffled with the seed
+    Stratified,
+}
+
+/// How in-context examples are picked from the train set
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub enum ExampleSelectionStrategy {
+    // Cycles over every (sensible value, label) cell
+    Balanced,
+    // Random rows, regardless of group or label
+    Random,
+    // Rows most similar to the test row
+    NearestNeighbour,
+    // Only rows from the same group as the test row, alternating labels
+    SameGroup,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct FewShotConfiguration {
+    // Number of examples. 0 means zero-shot
+    pub shots: u32,
+    pub strategy: ExampleSelectionStrategy,
+}
+
+impl Default for FewShotConfiguration {
+    fn default() -> Self {
+        Self {
+            shots: 4,
+            strategy: ExampleSelectionStrategy::Balanced,
+        }
+    }
+}
+
+/// Where counter factual queries come from in LLM fairness evaluations
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub enum CounterFactualSource {
+    // The sensible attribute of the test row is swapped on the fly
+    Swap,
+    // Prepared rows from the dataset counter factual test CSV, matched by id
+    TestSet,
+}
+
+/// Optional settings for LLM fairness evaluations
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq, Default)]
+pub struct LLMFairnessEvaluationOptions {
+    pub counter_factual_source: Option<CounterFactualSource>,
+    // Custom prompt template. Cannot be used together with prompt_template_name
+    pub prompt_template: Option<String>,
+    // Name of a template stored in the prompt template library
+    pub prompt_template_name: Option<String>,
+    // Number of examples and how they are picked. Defaults to 4 balanced examples
+    pub few_shot: Option<FewShotConfiguration>,
+    // Overrides the dataset answer extraction strategy
+    pub answer_extraction: Option<AnswerExtractionStrategy>,
+    // Overrides the default generation parameters
+    pub generation_parameters: Option<GenerationParameters>,
+    // How test rows are picked. Defaults to Sequential
+    pub test_row_sampling: Option<TestRowSampling>,
+    // Sensible attributes evaluated together with the main one. Each one adds calls per test row.
+    // Defaults to the ones declared by the dataset, built-in datasets declare none
+    pub additional_sensible_attributes: Option<Vec<AdditionalSensibleAttribute>>,
+}
+
+/// Named LLM fairness prompt template, stored in the template library
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct LLMFairnessPromptTemplate {
+    pub name: String,
+    pub description: String,
+    // Should contain the <EXAMPLE_0> to <EXAMPLE_3> and *?* placeholders
+    pub template: String,
+    pub timestamp: u64,
+}
+
+impl Storable for LLMFairnessPromptTemplate {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Mean, standard deviation and range of a metric across several runs
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct MetricStatistics {
+    pub mean: f32,
+    // Sample standard deviation. 0 if there is only one value
+    pub std_dev: f32,
+    pub min: f32,
+    pub max: f32,
+    // Number of runs where the metric could be calculated
+    pub n: u32,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct PrivilegedIndexStatistics {
+    pub variable_name: String,
+    pub statistics: MetricStatistics,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct AverageMetricsStatistics {
+    pub statistical_parity_difference: Option<MetricStatistics>,
+    pub disparate_impact: Option<MetricStatistics>,
+    pub average_odds_difference: Option<MetricStatistics>,
+    pub equal_opportunity_difference: Option<MetricStatistics>,
+}
+
+/// Statistics of every field of `Metrics` across several runs
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct MetricsStatistics {
+    pub statistical_parity_difference: Option<Vec<PrivilegedIndexStatistics>>,
+    pub disparate_impact: Option<Vec<PrivilegedIndexStatistics>>,
+    pub average_odds_difference: Option<Vec<PrivilegedIndexStatistics>>,
+    pub equal_opportunity_difference: Option<Vec<PrivilegedIndexStatistics>>,
+    pub average_metrics: AverageMetricsStatistics,
+    pub accuracy: Option<MetricStatistics>,
+    pub precision: Option<MetricStatistics>,
+    pub recall: Option<MetricStatistics>,
+}
+
+/// Group of LLM fairness evaluations of the same dataset with different seeds
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct MultiSeedEvaluationResult {
+    pub multi_seed_evaluation_id: u128,
+    pub dataset: String,
+    pub seeds: Vec<u32>,
+    // One evaluation and job for every seed, in the same order
+    pub model_evaluation_ids: Vec<u128>,
+    pub job_ids: Vec<u128>,
+    // Job that calculates the statistics once all the evaluations are done
+    pub job_id: u128,
+    pub timestamp: u64,
+    pub finished: bool,
+    pub metrics: Option<MetricsStatistics>,
+    pub counter_factual_change_rate: Option<MetricStatistics>,
+}
+
+/// Instructions written before the context in both kinds of context association tests
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct CatPromptTemplates {
+    pub intrasentence: String,
+    pub intersentence: String,
+}
+
+/// Statistics of the context association test scores across several runs
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct CatMetricsStatistics {
+    pub icat_score_general: Option<MetricStatistics>,
+    pub icat_score_intra: Option<MetricStatistics>,
+    pub icat_score_inter: Option<MetricStatistics>,
+    pub icat_score_gender: Option<MetricStatistics>,
+    pub icat_score_race: Option<MetricStatistics>,
+    pub icat_score_profession: Option<MetricStatistics>,
+    pub icat_score_religion: Option<MetricStatistics>,
+    pub general_lms: Option<MetricStatistics>,
+    pub general_ss: Option<MetricStatistics>,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub enum PromptRobustnessTarget {
+    LLMFairness { dataset: String },
+    ContextAssociationTest,
+}
+
+/// Evaluation run with one of the prompt templates
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct PromptRobustnessRun {
+    // "original", "paraphrase_1", ... for the bundled templates, and "custom_1", ... for the passed ones
+    pub template_name: String,
+    pub job_id: u128,
+    // LLM fairness evaluation id or context association test id
+    pub evaluation_id: u128,
+}
+
+/// Group of evaluations with the same seed and different paraphrases of the instructions
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct PromptRobustnessResult {
+    pub prompt_robustness_evaluation_id: u128,
+    pub target: PromptRobustnessTarget,
+    pub seed: u32,
+    pub runs: Vec<PromptRobustnessRun>,
+    // Job that calculates the statistics once all the evaluations are done
+    pub job_id: u128,
+    pub timestamp: u64,
+    pub finished: bool,
+    // Statistics of the LLM fairness metrics across the templates
+    pub metrics: Option<MetricsStatistics>,
+    // Statistics of the context association test scores across the templates
+    pub cat_metrics: Option<CatMetricsStatistics>,
+    // Largest range (max - min) of the fairness metric across the templates:
+    // SPD for LLM fairness and general ICAT for context association tests
+    pub fairness_range: Option<f32>,
+    pub instability_threshold: f32,
+    // True if the fairness range is greater than the threshold
+    pub unstable: bool,
+}
+
+/// LLM fairness dataset registered at runtime, with the same fields as the built-in ones
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct LLMFairnessDatasetDefinition {
+    pub name: String,
+    // Should contain the <EXAMPLE_0> to <EXAMPLE_3> and *?* placeholders
+    pub prompt_template: String,
+    pub train_csv: String,
+    pub test_csv: String,
+    pub cf_test_csv: String,
+    pub sensible_attribute: String,
+    pub predict_attribute: String,
+    pub sensible_attribute_values: Vec<String>,
+    // First element corresponds to "false", second one corresponds to "true"
+    pub predict_attributes_values: Vec<String>,
+    pub binarized_sensible_attribute_column: Option<String>,
+    pub dataset_subject_label: String,
+    // Defaults to ExactMatch
+    pub answer_extraction: Option<AnswerExtractionStrategy>,
+    // Sensible attributes evaluated together with the main one
+    pub additional_sensible_attributes: Option<Vec<AdditionalSensibleAttribute>>,
+}
+
+/// Sensible attribute of a LLM fairness dataset, evaluated together with the main one
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct AdditionalSensibleAttribute {
+    // Column of the attribute
+    pub name: String,
+    // Values of the privileged group. Rows with any other value belong to the unprivileged group
+    pub privileged_values: Vec<String>,
+    // Values the attribute is swapped between in counter factual prompts. A row with one of them is swapped
+    // to the first other value of the list, and rows with any other value are not swapped
+    pub counter_factual_values: Vec<String>,
+}
+
+impl Storable for LLMFairnessDatasetDefinition {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 #[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
@@ -225,6 +608,276 @@ pub struct ClassifierModelData {
     pub data_points: Vec<DataPoint>,
     pub metrics: Metrics,
     pub metrics_history: Vec<Metrics>,
+    // Model that can recompute predictions, used for counter factual tests
+    pub predictor: Option<ClassifierPredictor>,
+    pub counter_factual: Option<ClassifierCounterFactualResult>,
+    pub remote_evaluations: Option<Vec<RemoteClassifierEvaluation>>,
+    pub individual_fairness: Option<IndividualFairnessResult>,
+    pub feature_attribution: Option<FeatureAttributionResult>,
+    pub dataset_evaluations: Option<Vec<SharedDatasetEvaluation>>,
+}
+
+/// Logistic regression classifier that can be evaluated inside the canister.
+/// `weights` are aligned with the `features` vector of the data points.
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct LogisticRegressionModel {
+    pub weights: Vec<f64>,
+    pub intercept: f64,
+    // Probabilities higher or equal than the threshold are predicted as positive
+    pub threshold: f64,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub enum ClassifierPredictor {
+    LogisticRegression(LogisticRegressionModel),
+    Http(HttpClassifierEndpoint),
+}
+
+/// Third party prediction endpoint called with HTTP outcalls.
+/// The request body is a JSON object with the features in `features_field`,
+/// as an array or, if `feature_names` is set, as an object with these keys.
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct HttpClassifierEndpoint {
+    pub url: String,
+    pub features_field: String,
+    pub feature_names: Option<Vec<String>>,
+    // JSON pointer to the prediction in the response, e.g. "/prediction" or "/predictions/0"
+    pub prediction_pointer: String,
+    // String predictions equal to this label are positive
+    pub positive_label: Option<String>,
+    // Numeric predictions higher or equal than the threshold are positive. Defaults to 0.5
+    pub threshold: Option<f64>,
+    // Config key holding a bearer token for the endpoint, if it requires one
+    pub api_key_config_key: Option<String>,
+}
+
+/// Rows sent to a remote classifier
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub enum RemoteClassifierRowSource {
+    // Data points stored in the model with the given timestamp
+    StoredDataPoints { timestamp: u64 },
+    // Rows of a shared benchmark dataset
+    SharedDataset { dataset_id: u128 },
+}
+
+/// Failed call to a classifier endpoint.
+/// `sensible_attribute` is set when the call was made with that attribute flipped.
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct RemoteClassifierCallError {
+    pub row: usize,
+    pub sensible_attribute: Option<String>,
+    pub message: String,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct RemoteClassifierEvaluation {
+    pub remote_evaluation_id: u128,
+    pub timestamp: u64,
+    pub source: RemoteClassifierRowSource,
+    pub endpoint_url: String,
+    pub max_queries: usize,
+    pub max_errors: u32,
+    pub errors: u32,
+    pub call_errors: Vec<RemoteClassifierCallError>,
+    // Data points with the predictions returned by the endpoint
+    pub data_points: Vec<DataPoint>,
+    pub counter_factual: bool,
+    // Predictions for every flipped sensible attribute, aligned with data_points
+    pub counter_factual_predictions: Vec<Vec<Option<bool>>>,
+    pub counter_factual_result: Option<ClassifierCounterFactualResult>,
+    pub metrics: Option<Metrics>,
+    pub finished: bool,
+    pub canceled: bool,
+}
+
+/// Counter factual flip test result for classifiers.
+/// There is one `CounterFactualModelEvaluationResult` for every sensible attribute
+/// in the privileged map, where index 0 is the unprivileged group and index 1 the privileged one.
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct ClassifierCounterFactualResult {
+    // Proportion of data points whose prediction changed when flipping any sensible attribute
+    pub change_rate_overall: f32,
+    pub total_data_points: u32,
+    pub sensible_attributes: Vec<CounterFactualModelEvaluationResult>,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub enum FeatureScaling {
+    None,
+    // Scales every feature to [0, 1]
+    MinMax,
+    // Scales every feature to mean 0 and standard deviation 1
+    Standard,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct IndividualFairnessDataPoint {
+    pub data_point_id: u128,
+    pub predicted: bool,
+    pub consistency: f32,
+    // Ids of the k nearest data points
+    pub neighbours: Vec<u128>,
+}
+
+/// k-nearest neighbours consistency score for a classifier
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct IndividualFairnessResult {
+    pub consistency_overall: f32,
+    pub k: u32,
+    pub scaling: FeatureScaling,
+    // Sensible feature indexes, not used in distances
+    pub excluded_features: Vec<u128>,
+    pub total_data_points: u32,
+    // Data points with the lowest consistency, sorted from lowest
+    pub most_inconsistent: Vec<IndividualFairnessDataPoint>,
+    pub timestamp: u64,
+}
+
+/// Average contribution (weight * value) of a feature to the score of a linear model
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct FeatureContribution {
+    pub feature_index: u128,
+    pub feature_name: Option<String>,
+    pub weight: f64,
+    pub privileged_mean: f64,
+    pub unprivileged_mean: f64,
+    // unprivileged_mean - privileged_mean, same sign convention as SPD
+    pub gap: f64,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct FeatureAttributionGroupResult {
+    pub sensible_attribute: String,
+    pub privileged_count: u32,
+    pub unprivileged_count: u32,
+    // Difference in the average score (log-odds) between groups, the sum of all gaps
+    pub score_gap: f64,
+    // Sorted by absolute gap, from highest
+    pub contributions: Vec<FeatureContribution>,
+    // Same values, only for data points with a positive target (related to EOD)
+    pub positive_target_score_gap: f64,
+    pub positive_target_contributions: Vec<FeatureContribution>,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct FeatureAttributionResult {
+    pub sensible_attributes: Vec<FeatureAttributionGroupResult>,
+    pub timestamp: u64,
+}
+
+/// Sensitive column of a shared dataset.
+/// Values higher than the threshold are privileged (or lower, if `greater_than` is false).
+/// If the threshold is not set, the middle of the range is used.
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct SensitiveColumn {
+    pub name: String,
+    pub feature_index: u128,
+    pub threshold: Option<f64>,
+    pub greater_than: bool,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct SharedDatasetRow {
+    pub row_id: u128,
+    pub features: Vec<f64>,
+    pub target: bool,
+}
+
+/// Benchmark dataset shared by several classifier models,
+/// so they can be compared on the same rows.
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct SharedDataset {
+    pub dataset_id: u128,
+    pub name: String,
+    pub description: String,
+    pub owner: Principal,
+    // Name of every feature column, aligned with the rows features
+    pub schema: Vec<String>,
+    pub target_name: String,
+    pub sensitive_columns: Vec<SensitiveColumn>,
+    pub rows: Vec<SharedDatasetRow>,
+    pub timestamp: u64,
+}
+
+impl SharedDataset {
+    pub fn privileged_map(&self) -> PrivilegedMap {
+        self.sensitive_columns
+            .iter()
+            .map(|column| (column.name.clone(), column.feature_index))
+            .collect()
+    }
+
+    /// Thresholds defined for the sensitive columns.
+    /// Returns None if no threshold is defined, so the default calculation is used.
+    pub fn thresholds(&self) -> Option<HashMap<String, (f64, bool)>> {
+        let thresholds: HashMap<String, (f64, bool)> = self
+            .sensitive_columns
+            .iter()
+            .filter_map(|column| {
+                column
+                    .threshold
+                    .map(|threshold| (column.name.clone(), (threshold, column.greater_than)))
+            })
+            .collect();
+
+        if thresholds.is_empty() {
+            None
+        } else {
+            Some(thresholds)
+        }
+    }
+
+    /// Returns the rows as data points, with the given predictions
+    pub fn to_data_points(&self, predictions: &[bool], timestamp: u64) -> Vec<DataPoint> {
+        let privileged_map = self.privileged_map();
+        self.rows
+            .iter()
+            .zip(predictions.iter())
+            .map(|(row, predicted)| DataPoint {
+                data_point_id: row.row_id,
+                target: row.target,
+                privileged_map: privileged_map.clone(),
+                predicted: *predicted,
+                features: row.features.clone(),
+                timestamp,
+            })
+            .collect()
+    }
+
+    /// Returns a version of the dataset without rows
+    pub fn prune(mut self) -> SharedDataset {
+        self.rows = Vec::new();
+        self
+    }
+}
+
+impl Storable for SharedDataset {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Predictions of a classifier over a shared dataset
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct SharedDatasetEvaluation {
+    pub dataset_id: u128,
+    pub timestamp: u64,
+    pub metrics: Metrics,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct DatasetLeaderboardEntry {
+    pub model_id: u128,
+    pub model_name: String,
+    pub timestamp: u64,
+    pub metrics: Metrics,
 }
 
 #[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
@@ -385,6 +1038,13 @@ pub struct LLMModelData {
     pub average_fairness_metrics: Option<AverageLLMFairnessMetrics>,
     pub language_evaluations: Vec<LanguageEvaluationResult>,
     pub inference_provider: Option<String>,
+    pub multi_seed_evaluations: Option<Vec<MultiSeedEvaluationResult>>,
+    pub persona_bias_evaluations: Option<Vec<PersonaBiasResult>>,
+    pub bbq_evaluations: Option<Vec<BBQResult>>,
+    pub crows_pairs_metrics_history: Option<Vec<CrowsPairsMetricsBag>>,
+    pub winobias_evaluations: Option<Vec<WinoBiasResult>>,
+    pub open_generation_evaluations: Option<Vec<OpenGenerationResult>>,
+    pub prompt_robustness_evaluations: Option<Vec<PromptRobustnessResult>>,
 }
 
 impl Default for LLMModelData {
@@ -397,6 +1057,13 @@ impl Default for LLMModelData {
             average_fairness_metrics: None,
             language_evaluations: Vec::new(),
             inference_provider: None,
+            multi_seed_evaluations: None,
+            persona_bias_evaluations: None,
+            bbq_evaluations: None,
+            crows_pairs_metrics_history: None,
+            winobias_evaluations: None,
+            open_generation_evaluations: None,
+            prompt_robustness_evaluations: None,
         }
     }
 }
@@ -478,14 +1145,23 @@ impl Storable for Model {
 impl Model {
     /// Returns a light-weight version of the model, without the heavy data
     /// Like data points, multiple models, etc.
-    /// Currently it only prunes LLM models.
+    /// Currently it prunes LLM and embedding models.
     pub fn prune(self) -> Model {
         match self.model_type {
             ModelType::LLM(_) => self.prune_llm_model(),
+            ModelType::Embedding(_) => self.prune_embedding_model(),
             _ => self,
         }
     }
 
+    /// Takes an embedding model and returns another model without the uploaded embeddings
+    pub fn prune_embedding_model(mut self) -> Model {
+        let mut model_data = get_embedding_model_data(&self);
+        model_data.embeddings = Vec::new();
+        self.model_type = ModelType::Embedding(model_data);
+        self
+    }
+
     /// Takes a LLM model and returns another model with pruned data
     /// Useful because data_points contain a lot of data
     /// And the protocol doesn't support to return so much data
@@ -523,6 +1199,61 @@ impl Model {
             })
             .collect();
 
+        model_data.persona_bias_evaluations = model_data.persona_bias_evaluations.map(|evaluations| {
+            evaluations
+                .into_iter()
+                .filter(|evaluation| evaluation.finished && !evaluation.canceled)
+                .map(|mut evaluation| {
+                    evaluation.data_points = Vec::new();
+                    evaluation
+                })
+                .collect()
+        });
+
+        model_data.bbq_evaluations = model_data.bbq_evaluations.map(|evaluations| {
+            evaluations
+                .into_iter()
+                .filter(|evaluation| evaluation.finished && !evaluation.canceled)
+                .map(|mut evaluation| {
+                    evaluation.data_points = Vec::new();
+                    evaluation
+                })
+                .collect()
+        });
+
+        model_data.crows_pairs_metrics_history = model_data.crows_pairs_metrics_history.map(|history| {
+            history
+                .into_iter()
+                .filter(|metrics_bag| metrics_bag.finished && !metrics_bag.canceled)
+                .map(|mut metrics_bag| {
+                    metrics_bag.data_points = Vec::new();
+                    metrics_bag
+                })
+                .collect()
+        });
+
+        model_data.winobias_evaluations = model_data.winobias_evaluations.map(|evaluations| {
+            evaluations
+                .into_iter()
+                .filter(|evaluation| evaluation.finished && !evaluation.canceled)
+                .map(|mut evaluation| {
+                    evaluation.data_points = Vec::new();
+                    evaluation
+                })
+                .collect()
+        });
+
+        model_data.open_generation_evaluations = model_data.open_generation_evaluations.map(|evaluations| {
+            evaluations
+                .into_iter()
+                .filter(|evaluation| evaluation.finished && !evaluation.canceled)
+                .map(|mut evaluation| {
+                    evaluation.data_points = Vec::new();
+                    evaluation
+                })
+                .collect()
+        });
+
         model_data.language_evaluations = model_data
             .language_evaluations
             .into_iter()
@@ -578,6 +1309,7 @@ pub struct HuggingFaceResponseItem {
 pub enum ModelType {
     Classifier(ClassifierModelData),
     LLM(LLMModelData),
+    Embedding(EmbeddingModelData),
 }
 
 // Useful function that panics in the case that the model is NOT a classifier
@@ -596,6 +1328,78 @@ pub fn get_llm_model_data(model: &Model) -> LLMModelData {
     }
 }
 
+// Useful function that panics in the case that the model is NOT an embedding model
+pub fn get_embedding_model_data(model: &Model) -> EmbeddingModelData {
+    match model.model_type {
+        ModelType::Embedding(ref model_data) => model_data.clone(),
+        _ => panic!("An embedding model was expected, got another type of model instead"),
+    }
+}
+
+// Embedding models
+#[derive(CandidType, CandidDeserialize, Clone, Debug)]
+pub struct EmbeddingModelData {
+    pub embeddings: Vec<Embedding>,
+    pub association_tests_history: Vec<EmbeddingAssociationResult>,
+}
+
+/// Embedding vector of a word or a sentence, calculated outside the canister
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct Embedding {
+    pub text: String,
+    pub vector: Vec<f32>,
+}
+
+/// Word runs the tests with the embeddings of the words (WEAT),
+/// and Sentence with the embeddings of sentences built with the words (SEAT)
+#[derive(CandidType, CandidDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum EmbeddingAssociationTestKind {
+    Word,
+    Sentence,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct EmbeddingAssociationWordSet {
+    pub name: String,
+    pub words: Vec<String>,
+}
+
+/// Tests whether the targets X and Y are associated differently with the attributes A and B
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct EmbeddingAssociationTest {
+    pub name: String,
+    pub description: String,
+    pub x: EmbeddingAssociationWordSet,
+    pub y: EmbeddingAssociationWordSet,
+    pub a: EmbeddingAssociationWordSet,
+    pub b: EmbeddingAssociationWordSet,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct EmbeddingAssociationTestResult {
+    pub test_name: String,
+    // Number of words or sentences of every set with an uploaded embedding
+    pub x_count: u32,
+    pub y_count: u32,
+    pub a_count: u32,
+    pub b_count: u32,
+    // Words or sentences of the test without an uploaded embedding
+    pub missing: Vec<String>,
+    pub test_statistic: Option<f32>,
+    pub effect_size: Option<f32>,
+    // One-sided p-value of the permutation test
+    pub p_value: Option<f32>,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct EmbeddingAssociationResult {
+    pub kind: EmbeddingAssociationTestKind,
+    pub permutations: u32,
+    pub seed: u32,
+    pub results: Vec<EmbeddingAssociationTestResult>,
+    pub timestamp: u64,
+}
+
 // LLMs
 #[derive(Serialize, Copy, Clone, PartialEq, Debug, CandidType, CandidDeserialize)]
 pub enum ContextAssociationTestResult {
@@ -648,6 +1452,12 @@ pub struct ContextAssociationTestMetricsBag {
     pub finished: bool,
     pub canceled: bool,
     pub job_id: Option<u128>,
+    // Id of the metrics bag whose stored answers were re-scored to create this one
+    pub rescored_from: Option<u128>,
+    // Strategy used to parse the answers. None means the option number is the first character
+    pub answer_extraction: Option<AnswerExtractionStrategy>,
+    // Effective generation parameters. None for tests run before they were stored
+    pub generation_parameters: Option<GenerationParameters>,
 }
 
 #[derive(Serialize, CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
@@ -665,6 +1475,8 @@ pub struct ContextAssociationTestDataPoint {
     pub error: bool,
     pub test_type: ContextAssociationTestType,
     pub timestamp: u64,
+    // None for data points stored before the bias type was saved
+    pub bias_type: Option<String>,
 }
 
 impl Storable for ClassifierModelData {
@@ -694,10 +1506,13 @@ impl Storable for LLMModelData {
 #[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
 pub struct LanguageEvaluationDataPoint {
     pub prompt: String,
+    // Chosen option, or the whole response when it isn't valid JSON
     pub response: Option<String>,
     pub valid: bool,
     pub error: bool,
     pub correct_answer: String,
+    // None for data points stored before the language was saved
+    pub language: Option<String>,
 }
 
 #[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
@@ -715,6 +1530,8 @@ pub struct LanguageEvaluationResult {
     pub finished: bool,
     pub canceled: bool,
     pub job_id: Option<u128>,
+    // Effective generation parameters. None for evaluations created before they were stored
+    pub generation_parameters: Option<GenerationParameters>,
 }
 
 #[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
@@ -810,6 +1627,387 @@ impl LanguageEvaluationMetrics {
     }
 }
 
+/// Demographic persona the tasks of a persona bias test are asked under
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct Persona {
+    pub name: String,
+    // Demographic attribute the persona is about (e.g. "age"). Personas of the same attribute are compared
+    pub attribute: String,
+    // First person description, like "I am a 60-year-old woman."
+    pub description: String,
+}
+
+/// Where the persona description is placed in the prompt
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub enum PersonaPlacement {
+    // In a system message with the instructions, as a description of the user.
+    // Only for inference providers with message roles
+    System,
+    // At the start of the question, as written by the user
+    User,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct PersonaBiasDataPoint {
+    pub task_id: String,
+    // None for the baseline, which has no persona
+    pub persona: Option<String>,
+    // System message sent before the prompt, with System placement
+    pub system_prompt: Option<String>,
+    pub prompt: String,
+    pub response: Option<String>,
+    // Index of the chosen option
+    pub answer: Option<usize>,
+    pub valid: bool,
+    pub error: bool,
+    pub refusal: bool,
+    pub timestamp: u64,
+}
+
+/// Answers of one persona, compared with the baseline answers
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct PersonaBiasMetrics {
+    // None for the baseline
+    pub persona: Option<String>,
+    pub attribute: Option<String>,
+    pub n: u32,
+    pub error_count: u32,
+    pub invalid_responses: u32,
+    pub refusals: u32,
+    // Refusals over the calls without errors
+    pub refusal_rate: Option<f32>,
+    // Valid answers that are correct, for tasks with a correct option
+    pub accuracy: Option<f32>,
+    // Valid answers that are different from the baseline answer of the same task
+    pub answer_change_rate: Option<f32>,
+    // Number of valid answers by option index
+    pub answer_distribution: Vec<u32>,
+    // Total variation distance between the answer distribution and the baseline one
+    pub answer_distribution_shift: Option<f32>,
+}
+
+/// Largest differences between the personas of the same attribute
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct PersonaBiasAttributeMetrics {
+    pub attribute: String,
+    pub personas: Vec<String>,
+    pub refusal_rate_gap: Option<f32>,
+    pub accuracy_gap: Option<f32>,
+    pub max_answer_change_rate: Option<f32>,
+    pub max_answer_distribution_shift: Option<f32>,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct PersonaBiasResult {
+    pub persona_bias_evaluation_id: u128,
+    pub timestamp: u64,
+    pub personas: Vec<Persona>,
+    pub persona_placement: PersonaPlacement,
+    pub max_tasks: usize,
+    pub seed: u32,
+    // Max call errors before the job is canceled. If it's 0, there is no limit
+    pub max_errors: u32,
+    pub data_points: Vec<PersonaBiasDataPoint>,
+    // Baseline first, then the personas in order. Calculated when the evaluation finishes
+    pub metrics: Vec<PersonaBiasMetrics>,
+    pub attribute_metrics: Vec<PersonaBiasAttributeMetrics>,
+    pub errors: u32,
+    pub finished: bool,
+    pub canceled: bool,
+    pub job_id: Option<u128>,
+    pub generation_parameters: GenerationParameters,
+}
+
+/// Context of a BBQ question. Ambiguous contexts don't have enough information to answer,
+/// so the correct answer is always the unknown option
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub enum BBQContextCondition {
+    Ambiguous,
+    Disambiguated,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct BBQDataPoint {
+    pub example_id: String,
+    pub category: String,
+    pub context_condition: BBQContextCondition,
+    // Negative questions ask who fits the stereotype (e.g. "Who is bad at math?")
+    pub negative_question: bool,
+    pub prompt: String,
+    pub response: Option<String>,
+    // Index of the chosen answer
+    pub answer: Option<usize>,
+    pub correct: bool,
+    // The answer is the stereotyped one: the target group for negative questions, the other group otherwise
+    pub biased: bool,
+    pub unknown: bool,
+    pub valid: bool,
+    pub error: bool,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq, Default)]
+pub struct BBQMetrics {
+    pub n: u32,
+    pub error_count: u32,
+    pub invalid_responses: u32,
+    pub ambiguous_n: u32,
+    pub disambiguated_n: u32,
+    // Correct answers over the responses without errors. Invalid responses count as incorrect
+    pub accuracy_ambiguous: Option<f32>,
+    pub accuracy_disambiguated: Option<f32>,
+    // From -1 (all answers go against the stereotype) to 1 (all answers follow it)
+    pub bias_score_ambiguous: Option<f32>,
+    pub bias_score_disambiguated: Option<f32>,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct BBQResult {
+    pub bbq_evaluation_id: u128,
+    pub timestamp: u64,
+    pub max_queries: usize,
+    pub seed: u32,
+    // Max call errors before the job is canceled. If it's 0, there is no limit
+    pub max_errors: u32,
+    pub data_points: Vec<BBQDataPoint>,
+    pub metrics: BBQMetrics,
+    pub metrics_per_category: Vec<(String, BBQMetrics)>,
+    pub errors: u32,
+    pub finished: bool,
+    pub canceled: bool,
+    pub job_id: Option<u128>,
+    pub generation_parameters: GenerationParameters,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum CrowsPairsResult {
+    Stereotype,
+    AntiStereotype,
+    // The response didn't choose any of the sentences
+    Other,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq, Default)]
+pub struct CrowsPairsMetrics {
+    pub stereotype: u32,
+    pub anti_stereotype: u32,
+    pub other: u32,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct CrowsPairsDataPoint {
+    pub pair_id: String,
+    pub bias_type: String,
+    pub prompt: String,
+    // Whether the stereotyping sentence was shown as the first option
+    pub stereotype_first: bool,
+    pub answer: Option<String>,
+    pub result: Option<CrowsPairsResult>,
+    pub error: bool,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct CrowsPairsMetricsBag {
+    pub crows_pairs_test_id: u128,
+    pub general: CrowsPairsMetrics,
+    pub bias_types: Vec<(String, CrowsPairsMetrics)>,
+    pub error_count: u32,
+    pub error_rate: f32,
+    pub total_queries: u32,
+    pub max_queries: usize,
+    pub timestamp: u64,
+    pub prompt_template: String,
+    pub seed: u32,
+    pub shuffle_questions: bool,
+    pub max_errors: u32,
+    // precalculated fields
+    // Stereotyping sentences chosen over the answers that chose a sentence. 0.5 means no preference
+    pub stereotype_preference_rate: Option<f32>,
+    pub stereotype_preference_rate_per_bias_type: Vec<(String, Option<f32>)>,
+    pub data_points: Vec<CrowsPairsDataPoint>,
+    pub finished: bool,
+    pub canceled: bool,
+    pub job_id: Option<u128>,
+    pub generation_parameters: GenerationParameters,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct WinoBiasDataPoint {
+    pub sentence_id: String,
+    // Occupation the pronoun refers to
+    pub occupation: String,
+    // Whether the pronoun matches the gender stereotype of the occupation
+    pub pro_stereotypical: bool,
+    pub prompt: String,
+    pub response: Option<String>,
+    // Index of the chosen occupation
+    pub answer: Option<usize>,
+    pub correct: bool,
+    pub valid: bool,
+    pub error: bool,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq, Default)]
+pub struct WinoBiasMetrics {
+    pub n: u32,
+    pub error_count: u32,
+    pub invalid_responses: u32,
+    // Correct answers over the responses without errors. Invalid responses count as incorrect
+    pub pro_stereotypical_accuracy: Option<f32>,
+    pub anti_stereotypical_accuracy: Option<f32>,
+    // Pro-stereotypical accuracy minus anti-stereotypical accuracy
+    pub accuracy_gap: Option<f32>,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct WinoBiasResult {
+    pub winobias_evaluation_id: u128,
+    pub timestamp: u64,
+    pub max_queries: usize,
+    pub seed: u32,
+    // Max call errors before the job is canceled. If it's 0, there is no limit
+    pub max_errors: u32,
+    pub data_points: Vec<WinoBiasDataPoint>,
+    pub metrics: WinoBiasMetrics,
+    pub metrics_per_occupation: Vec<(String, WinoBiasMetrics)>,
+    pub errors: u32,
+    pub finished: bool,
+    pub canceled: bool,
+    pub job_id: Option<u128>,
+    pub generation_parameters: GenerationParameters,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct OpenGenerationDataPoint {
+    pub prompt_id: String,
+    // Kind of group, like "gender" or "religion"
+    pub domain: String,
+    pub group: String,
+    pub prompt: String,
+    pub continuation: Option<String>,
+    // From -1 (negative) to 1 (positive)
+    pub sentiment: Option<f32>,
+    // From 0 (not toxic) to 1 (toxic)
+    pub toxicity: Option<f32>,
+    pub error: bool,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq, Default)]
+pub struct OpenGenerationGroupMetrics {
+    pub domain: String,
+    pub group: String,
+    pub n: u32,
+    pub error_count: u32,
+    pub mean_sentiment: Option<f32>,
+    // Continuations with a sentiment of at least 0.5, or at most -0.5
+    pub positive_rate: Option<f32>,
+    pub negative_rate: Option<f32>,
+    pub mean_toxicity: Option<f32>,
+    // Continuations with a toxicity of at least 0.5
+    pub toxic_rate: Option<f32>,
+}
+
+/// Highest minus lowest value of the metrics over the groups of a domain
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct OpenGenerationDomainMetrics {
+    pub domain: String,
+    pub groups: Vec<String>,
+    pub sentiment_disparity: Option<f32>,
+    pub negative_rate_disparity: Option<f32>,
+    pub toxicity_disparity: Option<f32>,
+    pub toxic_rate_disparity: Option<f32>,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct OpenGenerationResult {
+    pub open_generation_evaluation_id: u128,
+    pub timestamp: u64,
+    pub max_queries: usize,
+    pub seed: u32,
+    // Max call errors before the job is canceled. If it's 0, there is no limit
+    pub max_errors: u32,
+    pub data_points: Vec<OpenGenerationDataPoint>,
+    pub group_metrics: Vec<OpenGenerationGroupMetrics>,
+    pub domain_metrics: Vec<OpenGenerationDomainMetrics>,
+    pub errors: u32,
+    pub finished: bool,
+    pub canceled: bool,
+    pub job_id: Option<u128>,
+    pub generation_parameters: GenerationParameters,
+}
+
+/// Rule that marks a response as a refusal
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub enum RefusalRule {
+    // Phrase contained in the response, case insensitive
+    Phrase(String),
+    // Regular expression that matches the response
+    Regex(String),
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct RefusalDetectorConfig {
+    // Whether the built-in refusal phrases are used besides the rules
+    pub use_default_phrases: bool,
+    pub rules: Vec<RefusalRule>,
+}
+
+impl Default for RefusalDetectorConfig {
+    fn default() -> Self {
+        Self {
+            use_default_phrases: true,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Storable for RefusalDetectorConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq, Default)]
+pub struct RefusalRate {
+    // Stored responses, excluding call errors
+    pub n: u32,
+    pub refusals: u32,
+    pub refusal_rate: Option<f32>,
+}
+
+/// Refusal rates of the responses split by a field, like the sensible attribute value or the bias type
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct RefusalRateBreakdown {
+    pub name: String,
+    pub rates: Vec<(String, RefusalRate)>,
+    // Highest minus lowest refusal rate. Differential refusal is a fairness signal by itself
+    pub refusal_rate_gap: Option<f32>,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct EvaluationRefusalRates {
+    pub evaluation_id: u128,
+    pub overall: RefusalRate,
+    pub breakdowns: Vec<RefusalRateBreakdown>,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone, Debug, PartialEq)]
+pub struct ModelRefusalRates {
+    pub llm_fairness: Vec<EvaluationRefusalRates>,
+    pub context_association_tests: Vec<EvaluationRefusalRates>,
+    pub language_evaluations: Vec<EvaluationRefusalRates>,
+    pub persona_bias: Vec<EvaluationRefusalRates>,
+}
+
 // Object used to pass configuration to lower methods
 // In a single parameter
 pub struct HuggingFaceConfig {
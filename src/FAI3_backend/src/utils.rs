@@ -43,9 +43,14 @@ pub fn seeded_vector_shuffle<T: Clone>(mut elements: Vec<T>, seed: u32) -> Vec<T
     elements
 }
 
-pub fn clean_llm_response(text: &String) -> String {
+/// Removes the reasoning blocks of a LLM response, keeping line breaks
+pub fn remove_think_blocks(text: &str) -> String {
     let re = Regex::new(r"(?s)<think>.*?</think>").unwrap();
-    re.replace_all(text, "")
+    re.replace_all(text, "").to_string()
+}
+
+pub fn clean_llm_response(text: &str) -> String {
+    remove_think_blocks(text)
         .replace("\n", " ")
         .replace("\r", " ")
         .trim()